@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use crate::config::AuthConfig;
+use crate::error::KvError;
+use crate::noise_codec::load_key;
+use crate::protobuf::*;
+use crate::protobuf::request::Command;
+
+/// 握手时确认的客户端身份, 匹配到用户时不需要再发送Auth
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// TLS客户端证书中的CN
+    Certificate(String),
+    /// noise握手时对端的静态公钥
+    PublicKey(Vec<u8>),
+}
+
+/// 一个用户可以访问的key前缀, write中的前缀同时可以读
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    read: Vec<String>,
+    write: Vec<String>,
+    admin: bool,
+}
+
+impl User {
+    /// 检查请求涉及的所有key, 发布订阅按topic检查
+    pub fn check(&self, request: &Request) -> Result<(), KvError> {
+        let command = match &request.command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        match command {
+            Command::Get(RequestGet{key})
+            | Command::Exists(RequestExists{key})
+            | Command::Ttl(RequestTtl{key}) => self.read(key),
+            Command::Keys(RequestKeys{prefix}) => self.read(prefix),
+            Command::GetAll(RequestGetAll{keys}) => keys.iter().try_for_each(|key| self.read(key)),
+            Command::Subscribe(RequestSubscribe{topic}) => self.read(topic),
+            Command::Put(ResponsePut{key, ..})
+            | Command::Delete(RequestDelete{key})
+            | Command::Expire(RequestExpire{key, ..})
+            | Command::Persist(RequestPersist{key})
+            | Command::Cas(RequestCompareAndSwap{key, ..})
+            | Command::PutIfAbsent(RequestPutIfAbsent{key, ..})
+            | Command::Incr(RequestIncr{key, ..}) => self.write(key),
+            Command::PutAll(RequestPutAll{pairs}) => pairs.iter().try_for_each(|pair| self.write(&pair.key)),
+            Command::Publish(RequestPublish{topic, ..}) => self.write(topic),
+            Command::Txn(RequestTxn{ops}) => ops.iter().try_for_each(|op| match op.is_write() {
+                true => self.write(op.key()),
+                false => self.read(op.key()),
+            }),
+            // 只能取消自己连接上的订阅, 不需要检查
+            Command::Unsubscribe(_) | Command::Auth(_) => Ok(()),
+            Command::Replicate(_) | Command::Raft(_) | Command::AddMember(_) | Command::RemoveMember(_) => match self.admin {
+                true => Ok(()),
+                false => Err(KvError::PermissionDenied(format!("user {} is not admin", self.name))),
+            },
+        }
+    }
+
+    /// key前缀也可以传进来, 需要整个前缀在允许的范围内
+    fn read(&self, key: &str) -> Result<(), KvError> {
+        match self.admin || allowed(&self.read, key) || allowed(&self.write, key) {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!("user {} cannot read {}", self.name, key))),
+        }
+    }
+
+    fn write(&self, key: &str) -> Result<(), KvError> {
+        match self.admin || allowed(&self.write, key) {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!("user {} cannot write {}", self.name, key))),
+        }
+    }
+}
+
+fn allowed(prefixes: &[String], key: &str) -> bool {
+    prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+}
+
+/// 所有用户和它们的认证方式, 服务端启动时从配置加载
+#[derive(Debug, Default)]
+pub struct Acl {
+    // 只保存密码的sha256, 比较时长度固定
+    users: HashMap<String, (Option<[u8; 32]>, Arc<User>)>,
+    identities: HashMap<Identity, Arc<User>>,
+}
+
+impl Acl {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let mut acl = Acl::default();
+        for user in &config.users {
+            let entry = Arc::new(User {
+                name: user.name.clone(),
+                read: user.read.clone(),
+                write: user.write.clone(),
+                admin: user.admin,
+            });
+            if let Some(name) = &user.certificate {
+                acl.identities.insert(Identity::Certificate(name.clone()), entry.clone());
+            }
+            if let Some(path) = &user.public_key {
+                acl.identities.insert(Identity::PublicKey(load_key(path)?), entry.clone());
+            }
+            acl.users.insert(user.name.clone(), (user.password.as_deref().map(digest), entry));
+        }
+        Ok(acl)
+    }
+
+    /// 用户名和密码都正确时返回用户, 没有配置密码的用户不能用密码认证
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Arc<User>> {
+        let (expected, user) = self.users.get(name)?;
+        let expected = expected.as_ref()?;
+        constant_time_eq(expected, &digest(password)).then(|| user.clone())
+    }
+
+    pub fn identify(&self, identity: &Identity) -> Option<Arc<User>> {
+        self.identities.get(identity).cloned()
+    }
+}
+
+fn digest(password: &str) -> [u8; 32] {
+    Sha256::digest(password.as_bytes()).into()
+}
+
+// 比较固定长度的摘要, 时间和密码的内容和长度都无关, 避免通过响应时间猜出密码
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::UserConfig;
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let config = AuthConfig {
+            users: vec![
+                UserConfig {
+                    name: "team-a".into(),
+                    password: Some("secret".into()),
+                    read: vec!["shared/".into()],
+                    write: vec!["a/".into()],
+                    ..Default::default()
+                },
+                UserConfig { name: "alice".into(), certificate: Some("alice".into()), admin: true, ..Default::default() },
+            ],
+        };
+        let acl = Acl::new(&config).unwrap();
+        assert!(acl.authenticate("team-a", "wrong").is_none());
+        assert!(acl.authenticate("team-a", "secret-longer").is_none());
+        assert!(acl.authenticate("alice", "").is_none());
+        let user = acl.authenticate("team-a", "secret").unwrap();
+
+        assert!(user.check(&Request::new_put("a/1", b"v")).is_ok());
+        assert!(user.check(&Request::new_get("shared/1")).is_ok());
+        assert_eq!(user.check(&Request::new_put("shared/1", b"v")).unwrap_err().code(), 403);
+        assert!(user.check(&Request::new_get("b/1")).is_err());
+        assert!(user.check(&Request::new_keys("a/")).is_ok());
+        assert!(user.check(&Request::new_keys("")).is_err());
+        assert!(user.check(&Request::new_txn(vec![TxnOp::get("shared/1"), TxnOp::put("a/1", b"1", 0)])).is_ok());
+        assert!(user.check(&Request::new_txn(vec![TxnOp::get("a/1"), TxnOp::delete("shared/1")])).is_err());
+
+        let admin = acl.identify(&Identity::Certificate("alice".into())).unwrap();
+        assert_eq!(admin.name, "alice");
+        assert!(admin.check(&Request::new_put("b/1", b"v")).is_ok());
+        assert!(acl.identify(&Identity::Certificate("bob".into())).is_none());
+    }
+}
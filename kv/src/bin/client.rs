@@ -0,0 +1,345 @@
+use std::borrow::Cow;
+use std::time::Duration;
+use kv::client::KvClient;
+use kv::noise_codec::{Builder, NOISE_CODEC};
+use kv::tls::TlsClient;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::{Context, Editor, Helper, Hinter, Validator};
+use rustyline::history::DefaultHistory;
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// kv的命令行客户端, 不带子命令时进入交互模式
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Opts {
+    /// 服务端地址
+    #[arg(short, long, default_value = "localhost:8888")]
+    addr: String,
+    /// 客户端私钥文件, 不指定时使用临时密钥
+    #[arg(long, env = "KV_NOISE_KEY")]
+    key: Option<String>,
+    /// 服务端的公钥文件, 指定后会校验服务端的身份
+    #[arg(long, env = "KV_NOISE_PEER")]
+    peer: Option<String>,
+    /// 校验服务端证书的CA, 指定后使用TLS代替noise
+    #[arg(long, env = "KV_TLS_CA")]
+    tls_ca: Option<String>,
+    /// 客户端证书, 服务端要求mTLS时需要和--tls-key一起指定
+    #[arg(long, env = "KV_TLS_CERT")]
+    tls_cert: Option<String>,
+    /// 客户端私钥
+    #[arg(long, env = "KV_TLS_KEY")]
+    tls_key: Option<String>,
+    /// 服务端证书中的域名, 默认使用地址中的主机名
+    #[arg(long)]
+    tls_server_name: Option<String>,
+    /// 服务端支持时压缩连接上的消息
+    #[arg(long)]
+    compression: bool,
+    /// 服务端开启认证时的用户名
+    #[arg(long, env = "KV_USER")]
+    user: Option<String>,
+    /// 用户的密码
+    #[arg(long, env = "KV_PASSWORD")]
+    password: Option<String>,
+    /// 请求的超时时间, 单位秒
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 读取key
+    Get { key: String },
+    /// 写入key, --ttl 指定过期毫秒数
+    Put {
+        key: String,
+        value: String,
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// 当前的值等于expected时写入value
+    Cas { key: String, expected: String, value: String },
+    /// key不存在时写入, --ttl 指定过期毫秒数
+    PutNx {
+        key: String,
+        value: String,
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// 原子地加上delta
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// 原子地减去delta
+    Decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// 删除key
+    Del { key: String },
+    /// key是否存在
+    Exists { key: String },
+    /// 按前缀列出key
+    Keys {
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// 批量读取
+    Mget { keys: Vec<String> },
+    /// 设置过期毫秒数
+    Expire { key: String, ttl: u64 },
+    /// 查看剩余的过期时间
+    Ttl { key: String },
+    /// 去掉过期时间
+    Persist { key: String },
+    /// 发布消息到topic
+    Publish { topic: String, value: String },
+    /// 集群模式下增加成员
+    AddMember { id: u64, addr: String },
+    /// 集群模式下删除成员
+    RemoveMember { id: u64 },
+}
+
+// 交互模式下每一行都按子命令解析
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+struct Line {
+    #[command(subcommand)]
+    command: Command,
+}
+
+const COMMANDS: &[&str] = &[
+    "get", "put", "cas", "put-nx", "incr", "decr", "del", "exists", "keys", "mget", "expire", "ttl", "persist", "publish",
+    "add-member", "remove-member", "help", "exit",
+];
+
+#[tokio::main]
+async fn main() -> Result<()>{
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::filter::LevelFilter::from(Level::WARN))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let opts = Opts::parse();
+    let client = match &opts.tls_ca {
+        Some(ca) => {
+            let identity = match (&opts.tls_cert, &opts.tls_key) {
+                (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+                (None, None) => None,
+                _ => return Err(anyhow!("--tls-cert and --tls-key must be used together")),
+            };
+            // 去掉端口和ipv6地址的方括号
+            let host = opts.addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(&opts.addr);
+            let server_name = opts.tls_server_name.as_deref().unwrap_or(host.trim_matches(|c| c == '[' || c == ']'));
+            KvClient::connect_tls(&opts.addr, &TlsClient::new(ca, server_name, identity)?).await?
+        }
+        None => {
+            let noise = Builder::new(NOISE_CODEC, true)
+                .key_files(opts.key.as_deref(), opts.peer.as_deref())?
+                .compression(opts.compression);
+            KvClient::connect(&opts.addr, noise).await?
+        }
+    }.timeout(Duration::from_secs(opts.timeout));
+    if let Some(user) = &opts.user {
+        client.auth(user, opts.password.as_deref().unwrap_or_default()).await?;
+    }
+    match opts.command {
+        Some(command) => println!("{}", execute(&client, command).await?),
+        None => repl(&client, &opts.addr).await?,
+    }
+    Ok(())
+}
+
+async fn execute(client: &KvClient, command: Command) -> Result<String> {
+    let output = match command {
+        Command::Get { key } => show(client.get(&key).await?),
+        Command::Put { key, value, ttl } => {
+            match ttl {
+                Some(ttl) => client.put_ex(&key, value.as_bytes(), Duration::from_millis(ttl)).await?,
+                None => client.put(&key, value.as_bytes()).await?,
+            }
+            "OK".to_owned()
+        }
+        Command::Cas { key, expected, value } => client.cas(&key, expected.as_bytes(), value.as_bytes()).await?.to_string(),
+        Command::PutNx { key, value, ttl } => {
+            client.put_if_absent(&key, value.as_bytes(), ttl.map(Duration::from_millis)).await?.to_string()
+        }
+        Command::Incr { key, delta } => client.incr(&key, delta).await?.to_string(),
+        Command::Decr { key, delta } => client.incr(&key, delta.checked_neg().unwrap_or(i64::MAX)).await?.to_string(),
+        Command::Del { key } => show(client.del(&key).await?),
+        Command::Exists { key } => client.exists(&key).await?.to_string(),
+        Command::Keys { prefix } => lines(client.keys(&prefix).await?),
+        Command::Mget { keys } => {
+            let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+            let pairs = client.get_all(&keys).await?;
+            lines(pairs.into_iter().map(|p| format!("{} => {}", p.key, String::from_utf8_lossy(&p.value))))
+        }
+        Command::Expire { key, ttl } => client.expire(&key, Duration::from_millis(ttl)).await?.to_string(),
+        Command::Ttl { key } => match client.ttl(&key).await? {
+            Some(ttl) => format!("{}ms", ttl.as_millis()),
+            None => "(none)".to_owned(),
+        },
+        Command::Persist { key } => client.persist(&key).await?.to_string(),
+        Command::Publish { topic, value } => {
+            client.publish(&topic, value.as_bytes()).await?;
+            "OK".to_owned()
+        }
+        Command::AddMember { id, addr } => {
+            client.add_member(id, &addr).await?;
+            "OK".to_owned()
+        }
+        Command::RemoveMember { id } => {
+            client.remove_member(id).await?;
+            "OK".to_owned()
+        }
+    };
+    Ok(output)
+}
+
+fn show(value: Option<Vec<u8>>) -> String {
+    match value {
+        Some(v) => String::from_utf8_lossy(&v).to_string(),
+        None => "(nil)".to_owned(),
+    }
+}
+
+fn lines(items: impl IntoIterator<Item = String>) -> String {
+    let items: Vec<String> = items.into_iter().collect();
+    match items.is_empty() {
+        true => "(empty)".to_owned(),
+        false => items.join("\n"),
+    }
+}
+
+async fn repl(client: &KvClient, addr: &str) -> Result<()> {
+    let mut editor: Editor<CommandHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(CommandHelper));
+    let history = std::env::var("HOME").map(|home| format!("{}/.kv_history", home)).ok();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    let prompt = format!("{}> ", addr);
+    loop {
+        // readline会阻塞当前线程
+        let line = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        if line == "exit" || line == "quit" {
+            break;
+        }
+        let words = match split_line(line) {
+            Ok(words) => words,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        // help和参数错误都会通过clap的错误输出
+        match Line::try_parse_from(words) {
+            Ok(Line { command }) => match execute(client, command).await {
+                Ok(output) => println!("{}", output),
+                Err(e) => println!("(error) {}", e),
+            },
+            Err(e) => println!("{}", e),
+        }
+    }
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+// 按空白切分, 支持用双引号包含空格
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut has_word = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_word {
+                    words.push(std::mem::take(&mut current));
+                    has_word = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(anyhow!("unclosed quote"));
+    }
+    if has_word {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+// 补全第一个单词的命令名
+#[derive(Helper, Hinter, Validator)]
+struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        let candidates = COMMANDS.iter()
+            .filter(|c| c.starts_with(prefix))
+            .map(|c| Pair { display: c.to_string(), replacement: format!("{} ", c) })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Highlighter for CommandHelper {
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(&'s self, prompt: &'p str, _default: bool) -> Cow<'b, str> {
+        Cow::Owned(format!("\x1b[1;32m{}\x1b[0m", prompt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_line() {
+        assert_eq!(split_line("put k  \"a b\"").unwrap(), vec!["put", "k", "a b"]);
+        assert_eq!(split_line("put k \"\"").unwrap(), vec!["put", "k", ""]);
+        assert!(split_line("put k \"a").is_err());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let Line { command } = Line::try_parse_from(split_line("put k v --ttl 10").unwrap()).unwrap();
+        assert!(matches!(command, Command::Put { ttl: Some(10), .. }));
+    }
+}
@@ -0,0 +1,13 @@
+use std::fs;
+use anyhow::Result;
+use kv::noise_codec::generate_keypair;
+
+// 生成noise使用的静态密钥对: `keygen server` 会生成 server.key 和 server.pub
+fn main() -> Result<()> {
+    let name = std::env::args().nth(1).unwrap_or_else(|| "kv".to_owned());
+    let keypair = generate_keypair()?;
+    fs::write(format!("{}.key", name), &keypair.private)?;
+    fs::write(format!("{}.pub", name), &keypair.public)?;
+    println!("generated {}.key and {}.pub", name, name);
+    Ok(())
+}
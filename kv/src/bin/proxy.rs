@@ -0,0 +1,78 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use kv::noise_codec::{Builder, NOISE_CODEC};
+use kv::proxy::{serve_proxy, Proxy, DEFAULT_VNODES};
+
+/// kv的分片代理, 客户端使用和服务端一样的协议, 按key的一致性哈希转发到后端
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Opts {
+    /// 监听地址
+    #[arg(short, long, default_value = "0.0.0.0:8887")]
+    listen: String,
+    /// 后端kv服务的地址, 多个用逗号分隔
+    #[arg(short, long, required = true, value_delimiter = ',')]
+    backends: Vec<String>,
+    /// 每个后端在哈希环上的虚拟节点数
+    #[arg(long, default_value_t = DEFAULT_VNODES)]
+    vnodes: usize,
+    /// 日志级别: trace/debug/info/warn/error
+    #[arg(long, default_value = "info")]
+    log_level: String,
+    /// 代理的私钥文件, 同时用于客户端和后端的连接
+    #[arg(long, env = "KV_NOISE_KEY")]
+    noise_key: Option<String>,
+    /// 只允许这个公钥的客户端连接
+    #[arg(long, env = "KV_NOISE_PEER")]
+    noise_peer: Option<String>,
+    /// 后端的公钥文件, 指定后会校验后端的身份
+    #[arg(long)]
+    backend_key: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let level = LevelFilter::from_str(&opts.log_level)
+        .map_err(|_| anyhow!("invalid log level: {}", opts.log_level))?;
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let backend_noise = Builder::new(NOISE_CODEC, true)
+        .key_files(opts.noise_key.as_deref(), opts.backend_key.as_deref())?;
+    info!("proxy to backends {:?}", opts.backends);
+    let proxy = Arc::new(Proxy::new(opts.backends, opts.vnodes, backend_noise)?);
+    let noise = Builder::new(NOISE_CODEC, false)
+        .key_files(opts.noise_key.as_deref(), opts.noise_peer.as_deref())?;
+    info!("Starting proxy in [{:?}]", opts.listen);
+    let listener = TcpListener::bind(&opts.listen).await?;
+    loop {
+        let (stream, socket_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // 文件描述符用完之类暂时的错误, 等一会再继续接受连接
+            Err(e) => {
+                warn!("failed to accept connection: {:?}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        info!("accept a new connection: [{:?} accept]", socket_addr);
+        let proxy = proxy.clone();
+        let noise = noise.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_proxy(stream, proxy, noise).await {
+                warn!("connection [{:?}] closed with error: {:?}", socket_addr, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,490 @@
+use std::sync::Arc;
+use std::time::Duration;
+use clap::Parser;
+use tracing::{info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use kv::acl::Acl;
+use kv::http::serve_http;
+use kv::config::{ClusterConfig, MemberConfig, RateLimitConfig, ReplicationConfig, ServerConfig, StorageConfig, TlsConfig, WalConfig};
+use kv::limit::Limiter;
+use kv::noise_codec::{Builder, NOISE_CODEC};
+use kv::raft::{RaftLog, RaftNode};
+use kv::replication::follow;
+use kv::resp::serve_resp;
+use kv::server::{serve_connection, serve_tls_connection, wait_for_signal};
+use kv::service::ServerState;
+use kv::storage::{Compressed, MemTable, SledDb, Storage};
+use kv::tls::TlsServer;
+use kv::wal::Wal;
+use anyhow::{anyhow, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+// accept失败通常是文件描述符用完之类暂时的错误, 等一会再继续接受连接
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// kv服务端, 命令行参数会覆盖配置文件中的同名配置
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Opts {
+    /// toml配置文件
+    #[arg(short, long)]
+    config: Option<String>,
+    /// 监听地址
+    #[arg(short, long)]
+    listen: Option<String>,
+    /// 兼容redis协议的监听地址
+    #[arg(long)]
+    resp_listen: Option<String>,
+    /// HTTP网关的监听地址
+    #[arg(long)]
+    http_listen: Option<String>,
+    /// prometheus指标的监听地址
+    #[arg(long)]
+    metrics_listen: Option<String>,
+    /// 日志级别: trace/debug/info/warn/error
+    #[arg(long)]
+    log_level: Option<String>,
+    /// 单个帧的最大字节数
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+    /// 单条消息的最大字节数, 超过一个帧的消息会分成多个帧发送
+    #[arg(long)]
+    max_message_size: Option<usize>,
+    /// 不压缩连接上的消息
+    #[arg(long)]
+    no_compression: bool,
+    /// 存储类型: memory/sled
+    #[arg(long)]
+    storage: Option<String>,
+    /// 存储时压缩比较大的value
+    #[arg(long)]
+    storage_compression: bool,
+    /// sled存储的目录
+    #[arg(long)]
+    path: Option<String>,
+    /// 开启预写日志, 日志和快照保存在这个目录
+    #[arg(long)]
+    wal_dir: Option<String>,
+    /// 作为follower从这个地址的leader复制数据
+    #[arg(long)]
+    replica_of: Option<String>,
+    /// leader的公钥文件
+    #[arg(long)]
+    leader_key: Option<String>,
+    /// 集群模式下当前节点的id
+    #[arg(long)]
+    cluster_id: Option<u64>,
+    /// 集群初次启动时的成员, 格式为 1=127.0.0.1:8881,2=127.0.0.1:8882
+    #[arg(long, value_parser = parse_member, value_delimiter = ',')]
+    cluster_members: Vec<MemberConfig>,
+    /// raft日志所在的目录
+    #[arg(long)]
+    cluster_dir: Option<String>,
+    /// 集群其它节点的公钥文件, 没有开启认证时必须指定
+    #[arg(long)]
+    cluster_peer: Option<String>,
+    /// 最多同时处理的连接数, 0表示不限制
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// 连接超过多少秒没有收发数据时断开, 0表示不断开
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// 每个客户端地址每秒最多的请求数
+    #[arg(long)]
+    rate_limit: Option<u32>,
+    /// 服务端私钥文件
+    #[arg(long, env = "KV_NOISE_KEY")]
+    noise_key: Option<String>,
+    /// 只允许这个公钥的客户端连接
+    #[arg(long, env = "KV_NOISE_PEER")]
+    noise_peer: Option<String>,
+    /// PEM格式的服务端证书, 和--tls-key一起指定时客户端的连接使用TLS
+    #[arg(long, env = "KV_TLS_CERT")]
+    tls_cert: Option<String>,
+    /// PEM格式的服务端私钥
+    #[arg(long, env = "KV_TLS_KEY")]
+    tls_key: Option<String>,
+    /// 签发客户端证书的CA, 指定后要求客户端提供证书
+    #[arg(long, env = "KV_TLS_CLIENT_CA")]
+    tls_client_ca: Option<String>,
+}
+
+impl Opts {
+    fn into_config(self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(resp_listen) = self.resp_listen {
+            config.resp_listen = Some(resp_listen);
+        }
+        if let Some(http_listen) = self.http_listen {
+            config.http_listen = Some(http_listen);
+        }
+        if let Some(metrics_listen) = self.metrics_listen {
+            config.metrics_listen = Some(metrics_listen);
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+        if let Some(size) = self.max_frame_size {
+            config.max_frame_size = size;
+        }
+        if let Some(size) = self.max_message_size {
+            config.max_message_size = size;
+        }
+        if self.no_compression {
+            config.compression = false;
+        }
+        if self.storage_compression {
+            config.storage_compression = true;
+        }
+        let path = self.path.or(match &config.storage {
+            StorageConfig::Sled { path } => Some(path.clone()),
+            StorageConfig::Memory => None,
+        });
+        match self.storage.as_deref() {
+            None => {}
+            Some("memory") => config.storage = StorageConfig::Memory,
+            Some("sled") => config.storage = StorageConfig::Sled {
+                path: path.unwrap_or_else(|| "/tmp/kvserver".to_owned()),
+            },
+            Some(other) => return Err(anyhow::anyhow!("unknown storage: {}", other)),
+        }
+        if let Some(dir) = self.wal_dir {
+            match config.wal.as_mut() {
+                Some(wal) => wal.dir = dir,
+                None => config.wal = Some(WalConfig { dir, snapshot_interval: 60, sync: false }),
+            }
+        }
+        if let Some(leader) = self.replica_of {
+            match config.replication.as_mut() {
+                Some(replication) => replication.leader = leader,
+                None => config.replication = Some(ReplicationConfig { leader, leader_public_key: None }),
+            }
+        }
+        if let (Some(key), Some(replication)) = (self.leader_key, config.replication.as_mut()) {
+            replication.leader_public_key = Some(key);
+        }
+        if let Some(id) = self.cluster_id {
+            match config.cluster.as_mut() {
+                Some(cluster) => cluster.id = id,
+                None => {
+                    let dir = self.cluster_dir.clone()
+                        .ok_or_else(|| anyhow::anyhow!("--cluster-dir is required in cluster mode"))?;
+                    config.cluster = Some(ClusterConfig { id, dir, members: vec![], peer_public_key: None });
+                }
+            }
+        }
+        if let Some(cluster) = config.cluster.as_mut() {
+            if let Some(dir) = self.cluster_dir {
+                cluster.dir = dir;
+            }
+            if !self.cluster_members.is_empty() {
+                cluster.members = self.cluster_members;
+            }
+            if let Some(peer) = self.cluster_peer {
+                cluster.peer_public_key = Some(peer);
+            }
+        }
+        if let Some(max) = self.max_connections {
+            config.max_connections = max;
+        }
+        if let Some(timeout) = self.idle_timeout {
+            config.idle_timeout = timeout;
+        }
+        if let Some(rate) = self.rate_limit {
+            config.rate_limit = Some(RateLimitConfig { requests_per_second: rate, burst: None });
+        }
+        if let Some(key) = self.noise_key {
+            config.noise.private_key = Some(key);
+        }
+        if let Some(peer) = self.noise_peer {
+            config.noise.peer_public_key = Some(peer);
+        }
+        match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => config.tls = Some(TlsConfig { cert, key, client_ca: self.tls_client_ca }),
+            (None, None) => {}
+            _ => return Err(anyhow!("--tls-cert and --tls-key must be used together")),
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn parse_member(s: &str) -> Result<MemberConfig> {
+    let (id, addr) = s.split_once('=').ok_or_else(|| anyhow::anyhow!("member must be id=addr: {}", s))?;
+    Ok(MemberConfig { id: id.parse()?, addr: addr.to_owned() })
+}
+
+fn new_store<S: Storage>(store: S, compression: bool) -> Result<ServerState> {
+    match compression {
+        true => Ok(ServerState::new(Compressed::new(store)?)),
+        // 压缩格式的value不能直接读出, 开启之后不能再关闭
+        false if Compressed::is_compressed(&store)? => Err(anyhow!("storage was written with storage_compression, it cannot be disabled")),
+        false => Ok(ServerState::new(store)),
+    }
+}
+
+fn new_state(config: &ServerConfig) -> Result<ServerState> {
+    let compression = config.storage_compression;
+    let state = match &config.storage {
+        StorageConfig::Memory => new_store(MemTable::new(), compression)?,
+        StorageConfig::Sled { path } => {
+            info!("using sled storage in [{:?}]", path);
+            new_store(SledDb::new(path)?, compression)?
+        }
+    }.with_backlog(config.replication_backlog);
+    let state = match &config.auth {
+        Some(auth) => {
+            info!("authentication enabled with {} users", auth.users.len());
+            state.with_acl(Acl::new(auth)?)
+        }
+        None => state,
+    };
+    match &config.wal {
+        Some(wal) => {
+            info!("using wal in [{:?}]", wal.dir);
+            Ok(state.with_wal(Wal::open(&wal.dir, wal.sync)?)?)
+        }
+        None => Ok(state),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Opts::parse().into_config()?;
+    // 日志初始化
+    tracing_subscriber::registry()
+        .with(config.level()?)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let state = Arc::new(new_state(&config)?);
+    // 定时做快照, 快照之后日志会被清空
+    if let Some(wal) = &config.wal {
+        let snapshot = state.clone();
+        let period = Duration::from_secs(wal.snapshot_interval.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = snapshot.snapshot() {
+                    warn!("failed to snapshot: {:?}", e);
+                }
+            }
+        });
+    }
+    // follower只读, 后台从leader同步修改
+    if let Some(replication) = &config.replication {
+        state.set_follower(true);
+        let noise = Builder::new(NOISE_CODEC, true)
+            .key_files(config.noise.private_key.as_deref(), replication.leader_public_key.as_deref())?
+            .max_frame_len(config.max_frame_size)
+            .max_message_len(config.max_message_size)
+            .compression(config.compression);
+        info!("following leader [{:?}]", replication.leader);
+        tokio::spawn(follow(replication.leader.clone(), noise, state.clone()));
+    }
+    // 集群模式下写请求通过raft复制到多数节点之后再执行
+    if let Some(cluster) = &config.cluster {
+        let noise = Builder::new(NOISE_CODEC, true)
+            .key_files(config.noise.private_key.as_deref(), cluster.peer_public_key.as_deref())?
+            .max_frame_len(config.max_frame_size)
+            .max_message_len(config.max_message_size)
+            .compression(config.compression);
+        let members = cluster.members.iter().map(|m| (m.id, m.addr.clone())).collect();
+        info!("starting cluster node {} with raft log in [{:?}]", cluster.id, cluster.dir);
+        RaftNode::start(cluster.id, members, RaftLog::open(&cluster.dir)?, &state, noise)?;
+    }
+    // 后台定时清理过期的key
+    let reaper = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            match reaper.reap_expired() {
+                Ok(0) => {}
+                Ok(count) => info!("removed {} expired keys", count),
+                Err(e) => warn!("failed to remove expired keys: {:?}", e),
+            }
+        }
+    });
+    let noise = Builder::new(NOISE_CODEC, false)
+        .key_files(config.noise.private_key.as_deref(), config.noise.peer_public_key.as_deref())?
+        .max_frame_len(config.max_frame_size)
+        .max_message_len(config.max_message_size)
+        .compression(config.compression);
+    let tls = match &config.tls {
+        Some(tls) => Some(TlsServer::new(&tls.cert, &tls.key, tls.client_ca.as_deref())?
+            .max_message_len(config.max_message_size)),
+        None => None,
+    };
+    // prometheus指标, 退出时不需要等待
+    if let Some(addr) = &config.metrics_listen {
+        info!("Starting metrics endpoint in [{:?}]", addr);
+        let server = axum::Server::try_bind(&addr.parse()?)?
+            .serve(kv::metrics::router(state.clone()).into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("metrics endpoint stopped with error: {:?}", e);
+            }
+        });
+    }
+    // kv, RESP和HTTP的连接共用连接数和限流
+    let limiter = Arc::new(Limiter::new(&config));
+    // redis协议的监听, 和kv协议共用同一个ServerState
+    if let Some(addr) = &config.resp_listen {
+        info!("Starting resp listener in [{:?}]", addr);
+        let listener = TcpListener::bind(addr).await?;
+        let state = state.clone();
+        let limiter = limiter.clone();
+        tokio::spawn(async move {
+            // 收到退出信号之后不再接受新的连接
+            let mut shutdown = state.shutdown_signal();
+            loop {
+                let (mut stream, socket_addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("failed to accept resp connection: {:?}", e);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    _ = shutdown.recv() => break,
+                };
+                let limits = match limiter.admit(socket_addr.ip()) {
+                    Some(limits) => limits,
+                    None => {
+                        warn!("too many connections, reject resp connection [{:?}]", socket_addr);
+                        let _ = stream.write_all(b"-ERR max number of clients reached\r\n").await;
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_resp(stream, state, limits).await {
+                        warn!("resp connection [{:?}] closed with error: {:?}", socket_addr, e);
+                    }
+                });
+            }
+        });
+    }
+    // HTTP网关, 和kv的连接一样受连接数, 限流和空闲超时的限制, 退出时等待正在处理的请求完成
+    let http = match &config.http_listen {
+        Some(addr) => {
+            info!("Starting http gateway in [{:?}]", addr);
+            let listener = TcpListener::bind(addr).await?;
+            let router = kv::http::router(state.clone(), config.max_message_size);
+            let state = state.clone();
+            let limiter = limiter.clone();
+            Some(tokio::spawn(async move {
+                let mut shutdown = state.shutdown_signal();
+                loop {
+                    let (stream, socket_addr) = tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                warn!("failed to accept http connection: {:?}", e);
+                                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                                continue;
+                            }
+                        },
+                        _ = shutdown.recv() => break,
+                    };
+                    let limits = match limiter.admit(socket_addr.ip()) {
+                        Some(limits) => limits,
+                        None => {
+                            warn!("too many connections, reject http connection [{:?}]", socket_addr);
+                            continue;
+                        }
+                    };
+                    let (state, router) = (state.clone(), router.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_http(stream, state, router, limits).await {
+                            warn!("http connection [{:?}] closed with error: {:?}", socket_addr, e);
+                        }
+                    });
+                }
+            }))
+        }
+        None => None,
+    };
+    let addr = &config.listen;
+    info!("Starting server in [{:?}]", addr);
+    // tcp监听
+    let listener = TcpListener::bind(addr).await?;
+    let signal = wait_for_signal();
+    tokio::pin!(signal);
+    loop {
+        // 阻塞等待连接, 收到退出信号之后不再接受新的连接
+        let (stream, socket_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept connection: {:?}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            result = &mut signal => {
+                result?;
+                break;
+            }
+        };
+        // 超过最大连接数时直接关闭
+        let limits = match limiter.admit(socket_addr.ip()) {
+            Some(limits) => limits,
+            None => {
+                warn!("too many connections, reject [{:?}]", socket_addr);
+                continue;
+            }
+        };
+        info!("accept a new connection: [{:?} accept]", socket_addr);
+        let share = state.clone();
+        let noise = noise.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => serve_tls_connection(stream, share, tls, limits).await,
+                None => serve_connection(stream, share, noise, limits).await,
+            };
+            if let Err(e) = result {
+                warn!("connection [{:?}] closed with error: {:?}", socket_addr, e);
+            }
+        });
+    }
+    drop(listener);
+    shutdown(&state, http, Duration::from_secs(config.shutdown_timeout)).await
+}
+
+// 等待所有连接处理完正在执行的请求, 然后把数据刷到磁盘
+// 超时之后仍然刷盘, 但是返回错误, 进程以非0状态退出
+async fn shutdown(state: &ServerState, http: Option<JoinHandle<()>>, timeout: Duration) -> Result<()> {
+    info!("shutting down, waiting up to {:?} for in-flight requests", timeout);
+    state.shutdown();
+    let drained = tokio::time::timeout(timeout, async {
+        state.drained().await;
+        if let Some(http) = http {
+            let _ = http.await;
+        }
+    }).await;
+    if let Some(raft) = state.cluster() {
+        raft.stop();
+    }
+    state.flush()?;
+    match drained {
+        Ok(()) => {
+            info!("server stopped");
+            Ok(())
+        }
+        Err(_) => Err(anyhow!("connections did not finish within {:?}", timeout)),
+    }
+}
@@ -1,34 +1,458 @@
-mod protobuf;
-mod noise_codec;
-
-use protobuf::*;
-use prost::Message;
-use anyhow::Result;
-use tokio_util::codec::LengthDelimitedCodec;
-use tracing::{info, Level};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use futures::{StreamExt, SinkExt};
-
-
-#[tokio::main]
-async fn main() -> Result<()>{
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::LevelFilter::from(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-    let addr = "localhost:8888";
-    let stream = tokio::net::TcpStream::connect(addr).await?;
-    let mut stream = LengthDelimitedCodec::builder().length_field_length(2)
-        .new_framed(stream);
-    let request = Request::new_put("hello", b"world");
-    stream.send(request.into()).await?;
-    let request = Request::new_get("hello");
-    stream.send(request.into()).await?;
-    while let Some(Ok(buf)) = stream.next().await {
-        // Response::try_from(buf)?;
-        let response:Response = buf.try_into()?;
-        info!("response:[{:?}]", response);
-    }
-    Ok(())
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use thiserror::Error;
+use tracing::warn;
+use crate::noise_codec::{self, Builder};
+use crate::protobuf::*;
+use crate::server::Transport;
+use crate::tls::TlsClient;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// 定时清理已经超时的请求
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 请求在超时时间内没有收到响应, 和连接断开不同, 同一个连接上的其它请求不受影响
+#[derive(Debug, Error)]
+#[error("request timeout after {0:?}")]
+pub struct RequestTimeout(pub Duration);
+
+// 发给后台任务的请求, subscriber不为空时表示这是一个订阅请求
+struct Call {
+    request: Request,
+    sender: oneshot::Sender<Response>,
+    subscriber: Option<mpsc::Sender<Response>>,
+}
+
+/// kv的异步客户端, clone之后共用同一个连接
+/// 每个请求都会分配一个id, 服务端可能乱序返回, 后台任务按id把响应交给对应的请求,
+/// id为0的是订阅推送的消息, 按订阅id分发到对应的Subscription
+#[derive(Debug, Clone)]
+pub struct KvClient {
+    tx: mpsc::Sender<Call>,
+    timeout: Duration,
+}
+
+/// 一个topic的订阅, drop之后不再接收消息
+#[derive(Debug)]
+pub struct Subscription {
+    pub topic: String,
+    pub id: u32,
+    rx: mpsc::Receiver<Response>,
+}
+
+impl Subscription {
+    /// 等待下一条消息, 连接断开后返回None
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await.map(|r| r.value)
+    }
+}
+
+impl KvClient {
+    /// 连接服务端并完成noise握手
+    pub async fn connect(addr: impl ToSocketAddrs, noise: Builder) -> Result<Self> {
+        let stream = tokio::time::timeout(DEFAULT_TIMEOUT, TcpStream::connect(addr)).await??;
+        Self::from_stream(stream, noise).await
+    }
+
+    pub async fn from_stream<T>(stream: T, noise: Builder) -> Result<Self>
+        where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        let mut framed = noise.new_framed(stream)?;
+        tokio::time::timeout(DEFAULT_TIMEOUT, noise_codec::handshake(&mut framed)).await??;
+        Ok(Self::from_transport(framed))
+    }
+
+    /// 用TLS代替noise连接服务端
+    pub async fn connect_tls(addr: impl ToSocketAddrs, tls: &TlsClient) -> Result<Self> {
+        let stream = tokio::time::timeout(DEFAULT_TIMEOUT, TcpStream::connect(addr)).await??;
+        let framed = tokio::time::timeout(DEFAULT_TIMEOUT, tls.connect(stream)).await??;
+        Ok(Self::from_transport(framed))
+    }
+
+    // 后台任务负责在连接上收发消息, 写入由单独的任务完成, 写被阻塞时仍然继续读取响应,
+    // 否则两端同时发送大的消息时都在等对方读取, 互相卡住
+    fn from_transport(framed: impl Transport + Send + 'static) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Call>(128);
+        let (mut sink, mut framed) = framed.split();
+        let (writer, mut requests) = mpsc::unbounded_channel::<Bytes>();
+        let mut write_task = tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                sink.send(request).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        tokio::spawn(async move {
+            let mut next_id: u64 = 0;
+            let mut pending: HashMap<u64, (oneshot::Sender<Response>, Option<mpsc::Sender<Response>>)> = HashMap::new();
+            let mut subscribers: HashMap<u32, mpsc::Sender<Response>> = HashMap::new();
+            let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = sweep.tick() => {
+                        // 请求方超时之后会drop掉receiver, 不再等待响应
+                        pending.retain(|_, (sender, _)| !sender.is_closed());
+                    }
+                    result = &mut write_task => {
+                        if let Ok(Err(e)) = result {
+                            warn!("failed to send request: {:?}", e);
+                        }
+                        break;
+                    }
+                    call = rx.recv() => {
+                        // 所有的KvClient都被drop之后关闭连接
+                        let Call { request, sender, subscriber } = match call {
+                            Some(call) => call,
+                            None => break,
+                        };
+                        next_id += 1;
+                        if writer.send(request.with_id(next_id).into()).is_err() {
+                            break;
+                        }
+                        pending.insert(next_id, (sender, subscriber));
+                    }
+                    frame = framed.next() => {
+                        let response: Response = match frame.map(|f| f.map(|buf| buf.try_into())) {
+                            Some(Ok(Ok(response))) => response,
+                            _ => break,
+                        };
+                        if response.id == 0 {
+                            // 不能等待消费太慢的Subscription, 否则这个连接上的其它请求都会被阻塞
+                            let id = response.subscription_id;
+                            if let Some(subscriber) = subscribers.get(&id) {
+                                match subscriber.try_send(response) {
+                                    Ok(_) => {}
+                                    Err(mpsc::error::TrySendError::Full(response)) => {
+                                        warn!("subscription {} is full, drop message on topic [{}]", id, response.key);
+                                    }
+                                    // Subscription被drop之后不再分发
+                                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                                        subscribers.remove(&id);
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                        // 请求方已经超时的时候这里会发送失败, 直接丢弃
+                        if let Some((sender, subscriber)) = pending.remove(&response.id) {
+                            // 先登记订阅再返回响应, 避免漏掉紧接着推送的消息
+                            if let (Some(subscriber), 0) = (subscriber, response.code) {
+                                subscribers.insert(response.subscription_id, subscriber);
+                            }
+                            let _ = sender.send(response);
+                        }
+                    }
+                }
+            }
+            write_task.abort();
+        });
+        KvClient {
+            tx,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// 设置每个请求的超时时间
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 发送原始的请求, 返回服务端的响应, 请求id由客户端分配
+    pub async fn request(&self, request: Request) -> Result<Response> {
+        self.call(request, None).await
+    }
+
+    async fn call(&self, request: Request, subscriber: Option<mpsc::Sender<Response>>) -> Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let call = async {
+            let call = Call { request, sender, subscriber };
+            self.tx.send(call).await.map_err(|_| anyhow!("connection closed"))?;
+            receiver.await.map_err(|_| anyhow!("connection closed"))
+        };
+        tokio::time::timeout(self.timeout, call).await
+            .map_err(|_| RequestTimeout(self.timeout))?
+    }
+
+    /// 两个客户端是否共用同一个连接
+    pub fn same_connection(&self, other: &KvClient) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Request::new_get(key)).await?;
+        Ok(found(response)?.map(|r| r.value))
+    }
+
+    pub async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        check(self.request(Request::new_put(key, value)).await?)?;
+        Ok(())
+    }
+
+    /// 带过期时间的put
+    pub async fn put_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        check(self.request(Request::new_put_ex(key, value, ttl.as_millis() as u64)).await?)?;
+        Ok(())
+    }
+
+    /// 当前的值等于expected时写入value, 不相等或者key不存在时返回false
+    pub async fn cas(&self, key: &str, expected: &[u8], value: &[u8]) -> Result<bool> {
+        conditional(self.request(Request::new_cas(key, expected, value, 0)).await?)
+    }
+
+    /// key不存在时写入, 已经存在时返回false, 带ttl可以用作分布式锁
+    pub async fn put_if_absent(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<bool> {
+        let ttl = ttl.map(|t| t.as_millis() as u64).unwrap_or(0);
+        conditional(self.request(Request::new_put_if_absent(key, value, ttl)).await?)
+    }
+
+    /// 原子地加上delta, 返回新的值
+    pub async fn incr(&self, key: &str, delta: i64) -> Result<i64> {
+        Ok(check(self.request(Request::new_incr(key, delta)).await?)?.number)
+    }
+
+    /// 原子地执行一组操作, 返回每个操作的结果; 条件不满足时返回None, 所有的修改都不生效
+    pub async fn txn(&self, ops: Vec<TxnOp>) -> Result<Option<Vec<Response>>> {
+        let response = self.request(Request::new_txn(ops)).await?;
+        match response.code {
+            409 => Ok(None),
+            _ => Ok(Some(check(response)?.results)),
+        }
+    }
+
+    /// 删除key, 返回被删除的值
+    pub async fn del(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Request::new_delete(key)).await?;
+        Ok(found(response)?.map(|r| r.value))
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.request(Request::new_exists(key)).await?;
+        Ok(found(response)?.is_some())
+    }
+
+    pub async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let response = check(self.request(Request::new_keys(prefix)).await?)?;
+        Ok(response.pairs.into_iter().map(|p| p.key).collect())
+    }
+
+    /// 批量读取, 不存在的key不会出现在结果中
+    pub async fn get_all(&self, keys: &[&str]) -> Result<Vec<Kvpair>> {
+        let response = check(self.request(Request::new_get_all(keys)).await?)?;
+        Ok(response.pairs)
+    }
+
+    pub async fn put_all(&self, pairs: Vec<Kvpair>) -> Result<()> {
+        check(self.request(Request::new_put_all(pairs)).await?)?;
+        Ok(())
+    }
+
+    /// 用户名密码认证, 之后这个连接上的请求都按这个用户的权限检查
+    pub async fn auth(&self, username: &str, password: &str) -> Result<()> {
+        check(self.request(Request::new_auth(username, password)).await?)?;
+        Ok(())
+    }
+
+    /// 设置过期时间, key不存在时返回false
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let response = self.request(Request::new_expire(key, ttl.as_millis() as u64)).await?;
+        Ok(found(response)?.is_some())
+    }
+
+    /// 剩余的过期时间, key不存在或者没有过期时间时返回None
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let response = self.request(Request::new_ttl(key)).await?;
+        Ok(found(response)?
+            .filter(|r| r.ttl >= 0)
+            .map(|r| Duration::from_millis(r.ttl as u64)))
+    }
+
+    /// 去掉过期时间, key不存在时返回false
+    pub async fn persist(&self, key: &str) -> Result<bool> {
+        let response = self.request(Request::new_persist(key)).await?;
+        Ok(found(response)?.is_some())
+    }
+
+    pub async fn publish(&self, topic: &str, value: &[u8]) -> Result<()> {
+        check(self.request(Request::new_publish(topic, value)).await?)?;
+        Ok(())
+    }
+
+    /// 集群模式下增加成员, 需要发送给leader
+    pub async fn add_member(&self, id: u64, addr: &str) -> Result<()> {
+        check(self.request(Request::new_add_member(id, addr)).await?)?;
+        Ok(())
+    }
+
+    /// 集群模式下删除成员, 需要发送给leader
+    pub async fn remove_member(&self, id: u64) -> Result<()> {
+        check(self.request(Request::new_remove_member(id)).await?)?;
+        Ok(())
+    }
+
+    /// 订阅topic, 通过返回的Subscription接收消息
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
+        let (tx, rx) = mpsc::channel(128);
+        let response = check(self.call(Request::new_subscribe(topic), Some(tx)).await?)?;
+        Ok(Subscription {
+            topic: topic.to_owned(),
+            id: response.subscription_id,
+            rx,
+        })
+    }
+
+    /// 取消订阅, 订阅不存在时返回false
+    pub async fn unsubscribe(&self, subscription: &Subscription) -> Result<bool> {
+        let request = Request::new_unsubscribe(&subscription.topic, subscription.id);
+        Ok(found(self.request(request).await?)?.is_some())
+    }
+}
+
+// 0之外的code都当作错误
+fn check(response: Response) -> Result<Response> {
+    match response.code {
+        0 => Ok(response),
+        code => Err(anyhow!("server returned code {}: {}", code, response.message)),
+    }
+}
+
+// 404和409表示条件不满足
+fn conditional(response: Response) -> Result<bool> {
+    match response.code {
+        404 | 409 => Ok(false),
+        _ => check(response).map(|_| true),
+    }
+}
+
+// 404转换为None
+fn found(response: Response) -> Result<Option<Response>> {
+    match response.code {
+        404 => Ok(None),
+        _ => check(response).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use crate::noise_codec::NOISE_CODEC;
+    use crate::limit::ConnectionLimits;
+    use crate::server::serve_connection;
+    use crate::service::ServerState;
+    use super::*;
+
+    async fn start_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::default());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let noise = Builder::new(NOISE_CODEC, false).compression(true);
+                tokio::spawn(serve_connection(stream, state.clone(), noise, ConnectionLimits::default()));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_client_commands() {
+        let addr = start_server().await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true).compression(true)).await.unwrap();
+        client.put("hello", b"world").await.unwrap();
+        assert_eq!(client.get("hello").await.unwrap(), Some(b"world".to_vec()));
+        assert!(client.exists("hello").await.unwrap());
+        assert_eq!(client.keys("he").await.unwrap(), vec!["hello".to_owned()]);
+        assert_eq!(client.ttl("hello").await.unwrap(), None);
+        assert!(client.expire("hello", Duration::from_secs(60)).await.unwrap());
+        assert!(client.ttl("hello").await.unwrap().is_some());
+        assert_eq!(client.del("hello").await.unwrap(), Some(b"world".to_vec()));
+        assert_eq!(client.get("hello").await.unwrap(), None);
+
+        assert!(client.put_if_absent("lock", b"a", Some(Duration::from_secs(10))).await.unwrap());
+        assert!(!client.put_if_absent("lock", b"b", None).await.unwrap());
+        assert!(!client.cas("lock", b"b", b"c").await.unwrap());
+        assert!(client.cas("lock", b"a", b"c").await.unwrap());
+        assert_eq!(client.incr("counter", 2).await.unwrap(), 2);
+        assert_eq!(client.incr("counter", -3).await.unwrap(), -1);
+
+        let results = client.txn(vec![TxnOp::equals("counter", b"-1"), TxnOp::put("a", b"1", 0), TxnOp::get("a")]).await.unwrap();
+        assert_eq!(results.unwrap()[2].value, b"1");
+        assert!(client.txn(vec![TxnOp::exists("counter", false), TxnOp::delete("a")]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_large_value() {
+        let addr = start_server().await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        // 超过一个帧的value会分成多个帧发送
+        let value: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        client.put("blob", &value).await.unwrap();
+        assert_eq!(client.get("blob").await.unwrap(), Some(value));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_pipelined_large_values() {
+        let (a, b) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(b, Arc::new(ServerState::default()), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let client = KvClient::from_stream(a, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        // 请求和响应都远大于连接的缓冲区, 两端都要边写边读才不会互相卡住
+        let value = vec![7u8; 128 * 1024];
+        let keys: Vec<_> = (0..8).map(|i| format!("blob{}", i)).collect();
+        let puts = futures::future::join_all(keys.iter().map(|key| client.put(key, &value))).await;
+        assert!(puts.iter().all(|r| r.is_ok()));
+        let gets = futures::future::join_all(keys.iter().map(|key| client.get(key))).await;
+        assert!(gets.into_iter().all(|r| r.unwrap() == Some(value.clone())));
+    }
+
+    #[tokio::test]
+    async fn test_client_shared_connection() {
+        let addr = start_server().await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        let tasks: Vec<_> = (0..20).map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i);
+                client.put(&key, key.as_bytes()).await.unwrap();
+                assert_eq!(client.get(&key).await.unwrap(), Some(key.into_bytes()));
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(client.keys("key").await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_client_subscribe() {
+        let addr = start_server().await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        let publisher = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        let mut subscription = client.subscribe("news").await.unwrap();
+        publisher.publish("news", b"hello").await.unwrap();
+        // 订阅的同时还可以正常发送请求
+        client.put("k", b"v").await.unwrap();
+        assert_eq!(subscription.next().await, Some(b"hello".to_vec()));
+        assert!(client.unsubscribe(&subscription).await.unwrap());
+        assert!(!client.unsubscribe(&subscription).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscription() {
+        let addr = start_server().await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap().timeout(Duration::from_secs(1));
+        let mut subscription = client.subscribe("news").await.unwrap();
+        // 订阅的消息没有被消费时, 同一个连接上的请求不受影响
+        let published = futures::future::join_all((0..300).map(|_| client.publish("news", b"hello"))).await;
+        assert!(published.iter().all(|r| r.is_ok()));
+        client.put("k", b"v").await.unwrap();
+        assert_eq!(subscription.next().await, Some(b"hello".to_vec()));
+    }
 }
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, Result};
+
+/// 握手时协商的压缩算法名字, 目前只支持lz4
+pub const LZ4: &[u8] = b"lz4";
+/// 小于这个字节数的数据压缩的收益很小, 不压缩
+pub const MIN_COMPRESS_LEN: usize = 256;
+// 压缩结果前面4个字节是小端的原始长度
+const SIZE_LEN: usize = 4;
+
+/// lz4压缩, 结果的前4个字节是原始数据的长度
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    lz4_flex::compress_prepend_size(data)
+}
+
+/// 解压compress的结果, 原始长度超过max_len时报错, 避免很小的数据解压出大量内存
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    if data.len() < SIZE_LEN {
+        return Err(anyhow!("compressed data too short"));
+    }
+    let len = u32::from_le_bytes(data[..SIZE_LEN].try_into()?) as usize;
+    if len > max_len {
+        return Err(anyhow!("decompressed data too large: {}", len));
+    }
+    let value = lz4_flex::decompress(&data[SIZE_LEN..], len)?;
+    if value.len() != len {
+        return Err(anyhow!("decompressed size mismatch: expect {}, got {}", len, value.len()));
+    }
+    Ok(value)
+}
+
+/// 超过MIN_COMPRESS_LEN并且压缩之后变小时返回压缩的结果
+pub fn compress_if_smaller(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_COMPRESS_LEN {
+        return None;
+    }
+    let compressed = compress(data);
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let json = br#"{"name":"kv","tags":["a","b"]}"#.repeat(20);
+        let compressed = compress_if_smaller(&json).unwrap();
+        assert!(compressed.len() < json.len());
+        assert_eq!(decompress(&compressed, json.len()).unwrap(), json);
+        // 解压之后超过限制
+        assert!(decompress(&compressed, json.len() - 1).is_err());
+        assert!(decompress(b"ab", 100).is_err());
+        // 太短的数据不压缩
+        assert!(compress_if_smaller(b"hello").is_none());
+    }
+}
@@ -0,0 +1,342 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::filter::LevelFilter;
+use crate::noise_codec::{MAX_FRAME_LEN, MAX_MESSAGE_LEN};
+use crate::service::DEFAULT_BACKLOG;
+
+/// 服务端的配置, 从toml文件加载, 没有配置的项使用默认值
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// 兼容redis协议(RESP2/RESP3)的监听地址, 不加密, 不配置时不开启
+    pub resp_listen: Option<String>,
+    /// HTTP网关的监听地址, 不加密, 不配置时不开启
+    pub http_listen: Option<String>,
+    /// prometheus指标 `GET /metrics` 的监听地址, 不配置时不开启
+    pub metrics_listen: Option<String>,
+    pub log_level: String,
+    /// 单个帧的最大字节数, 不能超过65535
+    pub max_frame_size: usize,
+    /// 单条消息的最大字节数, 超过一个帧的消息会分成多个帧发送
+    pub max_message_size: usize,
+    /// 客户端请求时对连接上的消息做lz4压缩, 关闭后总是不压缩
+    pub compression: bool,
+    pub storage: StorageConfig,
+    /// 存储时压缩比较大的value, 开启之前写入的数据会在启动时转换格式, 开启之后不能再关闭
+    pub storage_compression: bool,
+    pub noise: NoiseConfig,
+    /// 客户端的连接使用TLS代替noise, 不配置时使用noise
+    /// 集群节点和follower只能用noise连接, 所以不能和cluster/replication一起配置, 配置了TLS的节点也不能被follower复制
+    pub tls: Option<TlsConfig>,
+    /// 预写日志, 不配置时不开启
+    pub wal: Option<WalConfig>,
+    /// 保留最近多少条修改记录, follower重连时可以从这里补齐, 超过之后只能全量同步
+    pub replication_backlog: usize,
+    /// 作为follower从leader复制, 不配置时作为leader运行
+    pub replication: Option<ReplicationConfig>,
+    /// 集群模式, 不配置时单机运行, 只能使用内存存储, 重启时从raft日志恢复数据
+    pub cluster: Option<ClusterConfig>,
+    /// 收到SIGTERM/SIGINT之后最多等待多少秒让正在处理的请求完成
+    pub shutdown_timeout: u64,
+    /// 最多同时处理的连接数, kv, RESP和HTTP的连接一起计算, 0表示不限制
+    pub max_connections: usize,
+    /// 连接超过多少秒没有收发数据时断开, 0表示不断开
+    pub idle_timeout: u64,
+    /// 按客户端地址限流, 不配置时不限流
+    pub rate_limit: Option<RateLimitConfig>,
+    /// 用户和按key前缀的权限, 不配置时不需要认证
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Memory,
+    Sled { path: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoiseConfig {
+    /// 服务端私钥文件, 不配置时使用临时密钥
+    pub private_key: Option<String>,
+    /// 只允许这个公钥的客户端连接
+    pub peer_public_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM格式的服务端证书链
+    pub cert: String,
+    /// PEM格式的服务端私钥
+    pub key: String,
+    /// 签发客户端证书的CA, 配置后要求客户端提供证书(mTLS), 证书中的CN作为客户端的身份
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalConfig {
+    /// 日志和快照所在的目录
+    pub dir: String,
+    /// 多少秒做一次快照
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval: u64,
+    /// 每次写日志之后是否fsync
+    #[serde(default)]
+    pub sync: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// leader的地址
+    pub leader: String,
+    /// leader的公钥文件, 指定后会校验leader的身份
+    #[serde(default)]
+    pub leader_public_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// 当前节点的id, 不能为0
+    pub id: u64,
+    /// raft日志和投票状态所在的目录
+    pub dir: String,
+    /// 集群初次启动时的成员, 之后以日志中的成员变更为准, 新加入的节点可以不配置
+    #[serde(default)]
+    pub members: Vec<MemberConfig>,
+    /// 其它节点的公钥文件, 指定后连接其它节点时会校验对方的身份
+    /// 没有开启认证时必须配置, 只接受用这个公钥连接的raft消息, 所有节点使用同一个密钥对
+    #[serde(default)]
+    pub peer_public_key: Option<String>,
+}
+
+/// 令牌桶限流, 同一个ip的所有连接共用一个桶
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// 每秒补充的令牌数, 也就是长期平均每秒最多的请求数
+    pub requests_per_second: u32,
+    /// 桶的容量, 允许的突发请求数, 不配置时和requests_per_second一样
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// 开启认证之后, 连接需要先用Auth命令认证, 或者在握手时用证书/公钥认证
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+/// key前缀为空字符串时表示所有的key, 发布订阅按topic的前缀检查
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserConfig {
+    pub name: String,
+    /// Auth命令的密码, 不配置时只能通过证书或者公钥认证
+    pub password: Option<String>,
+    /// TLS客户端证书中的身份(CN)
+    pub certificate: Option<String>,
+    /// noise客户端的公钥文件
+    pub public_key: Option<String>,
+    /// 只读的key前缀
+    pub read: Vec<String>,
+    /// 可以读写的key前缀
+    pub write: Vec<String>,
+    /// 可以读写所有的key, 并且可以执行复制和集群的命令, 集群的其它节点需要用公钥认证成admin
+    pub admin: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberConfig {
+    pub id: u64,
+    pub addr: String,
+}
+
+fn default_snapshot_interval() -> u64 {
+    60
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:8888".to_owned(),
+            resp_listen: None,
+            http_listen: None,
+            metrics_listen: None,
+            log_level: "info".to_owned(),
+            max_frame_size: MAX_FRAME_LEN,
+            max_message_size: MAX_MESSAGE_LEN,
+            compression: true,
+            storage: StorageConfig::Memory,
+            storage_compression: false,
+            noise: NoiseConfig::default(),
+            tls: None,
+            wal: None,
+            replication_backlog: DEFAULT_BACKLOG,
+            replication: None,
+            cluster: None,
+            shutdown_timeout: 30,
+            max_connections: 10000,
+            idle_timeout: 0,
+            rate_limit: None,
+            auth: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())?;
+        let config: Self = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.level()?;
+        if self.max_frame_size == 0 || self.max_frame_size > MAX_FRAME_LEN {
+            return Err(anyhow!("max_frame_size must be in 1..={}", MAX_FRAME_LEN));
+        }
+        if self.max_message_size == 0 {
+            return Err(anyhow!("max_message_size must be greater than 0"));
+        }
+        // follower的数据来自leader, 本地的日志不会记录同步过来的修改
+        if self.wal.is_some() && self.replication.is_some() {
+            return Err(anyhow!("wal cannot be used on a follower"));
+        }
+        if let Some(cluster) = &self.cluster {
+            // 集群的数据通过raft日志复制, 不能再开启预写日志和主从复制
+            if self.wal.is_some() || self.replication.is_some() {
+                return Err(anyhow!("cluster mode cannot be used with wal or replication"));
+            }
+            // 重启时从raft日志重新执行所有的修改, 磁盘存储中已经有这些修改, 再执行一次会重复
+            if self.storage != StorageConfig::Memory {
+                return Err(anyhow!("cluster mode only supports memory storage"));
+            }
+            if cluster.id == 0 || cluster.members.iter().any(|m| m.id == 0) {
+                return Err(anyhow!("cluster member id must not be 0"));
+            }
+            // 没有开启认证时靠固定的公钥确认raft消息来自其它节点
+            if self.auth.is_none() && (cluster.peer_public_key.is_none() || self.noise.private_key.is_none()) {
+                return Err(anyhow!("cluster mode without auth requires noise private_key and cluster peer_public_key"));
+            }
+        }
+        // 同一个端口只能用一种协议, 其它节点和follower用noise连接时无法和TLS握手
+        if self.tls.is_some() && (self.cluster.is_some() || self.replication.is_some()) {
+            return Err(anyhow!("tls cannot be used with cluster or replication"));
+        }
+        if let Some(auth) = &self.auth {
+            let mut names: Vec<&str> = auth.users.iter().map(|user| user.name.as_str()).collect();
+            names.sort_unstable();
+            if names.windows(2).any(|pair| pair[0] == pair[1]) {
+                return Err(anyhow!("duplicate user name in auth"));
+            }
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.requests_per_second == 0 || rate_limit.burst == Some(0) {
+                return Err(anyhow!("rate limit must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn level(&self) -> Result<LevelFilter> {
+        LevelFilter::from_str(&self.log_level)
+            .map_err(|_| anyhow!("invalid log level: {}", self.log_level))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() {
+        let config = ServerConfig::load("fixtures/server.toml").unwrap();
+        assert_eq!(config.listen, "0.0.0.0:8888");
+        assert_eq!(config.storage, StorageConfig::Sled { path: "/tmp/kvserver".to_owned() });
+        assert_eq!(config.noise.private_key.as_deref(), Some("server.key"));
+        assert_eq!(config.level().unwrap(), LevelFilter::INFO);
+        let wal = config.wal.unwrap();
+        assert_eq!((wal.dir.as_str(), wal.snapshot_interval, wal.sync), ("/tmp/kvwal", 60, false));
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let config: ServerConfig = toml::from_str("log_level = \"debug\"").unwrap();
+        assert_eq!(config.storage, StorageConfig::Memory);
+        assert_eq!(config.max_frame_size, MAX_FRAME_LEN);
+        assert_eq!(config.level().unwrap(), LevelFilter::DEBUG);
+
+        let config = ServerConfig { max_frame_size: 70000, ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_auth_config() {
+        let content = "[[auth.users]]\nname = \"team-a\"\npassword = \"secret\"\nread = [\"shared/\"]\nwrite = [\"a/\"]\n[[auth.users]]\nname = \"alice\"\ncertificate = \"alice\"\nadmin = true";
+        let config: ServerConfig = toml::from_str(content).unwrap();
+        let users = &config.auth.as_ref().unwrap().users;
+        assert_eq!((users[0].write.as_slice(), users[1].admin), (&["a/".to_owned()][..], true));
+        assert!(config.validate().is_ok());
+
+        let mut config = config;
+        config.auth.as_mut().unwrap().users[1].name = "team-a".to_owned();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_cluster_config() {
+        let content = "[noise]\nprivate_key = \"node.key\"\n[cluster]\nid = 1\ndir = \"/tmp/kvraft1\"\npeer_public_key = \"node.pub\"\n\
+            members = [{ id = 1, addr = \"127.0.0.1:8881\" }, { id = 2, addr = \"127.0.0.1:8882\" }]";
+        let config: ServerConfig = toml::from_str(content).unwrap();
+        let cluster = config.cluster.as_ref().unwrap();
+        assert_eq!((cluster.id, cluster.members.len()), (1, 2));
+        assert!(config.validate().is_ok());
+        // 没有认证也没有固定公钥时任何客户端都可以发送raft消息
+        let mut open = config.clone();
+        open.cluster.as_mut().unwrap().peer_public_key = None;
+        assert!(open.validate().is_err());
+        open.auth = Some(AuthConfig { users: vec![] });
+        assert!(open.validate().is_ok());
+
+        let wal = WalConfig { dir: "/tmp/kvwal".to_owned(), snapshot_interval: 60, sync: false };
+        assert!(ServerConfig { wal: Some(wal), ..config.clone() }.validate().is_err());
+        let storage = StorageConfig::Sled { path: "/tmp/kvserver".to_owned() };
+        assert!(ServerConfig { storage, ..config.clone() }.validate().is_err());
+        let tls = TlsConfig { cert: "server.pem".to_owned(), key: "server.key".to_owned(), client_ca: None };
+        assert!(ServerConfig { tls: Some(tls), ..config.clone() }.validate().is_err());
+        let mut config = config;
+        config.cluster.as_mut().unwrap().id = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_limit_config() {
+        let content = "max_connections = 100\nidle_timeout = 60\n[rate_limit]\nrequests_per_second = 1000";
+        let config: ServerConfig = toml::from_str(content).unwrap();
+        assert_eq!((config.max_connections, config.idle_timeout), (100, 60));
+        assert_eq!(config.rate_limit, Some(RateLimitConfig { requests_per_second: 1000, burst: None }));
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig { rate_limit: Some(RateLimitConfig { requests_per_second: 0, burst: None }), ..config };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_replication_config() {
+        let config: ServerConfig = toml::from_str("[replication]\nleader = \"10.0.0.1:8888\"").unwrap();
+        assert_eq!(config.replication.as_ref().unwrap().leader, "10.0.0.1:8888");
+        assert_eq!(config.replication_backlog, DEFAULT_BACKLOG);
+        assert!(config.validate().is_ok());
+
+        let wal = WalConfig { dir: "/tmp/kvwal".to_owned(), snapshot_interval: 60, sync: false };
+        let config = ServerConfig { wal: Some(wal), ..config };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use thiserror::Error;
+use crate::protobuf::Response;
+
+/// 服务端返回的错误, 通过Response的code和message告诉客户端
+///
+/// | code | 错误 |
+/// |------|------|
+/// | 400  | 请求无法解析, 或者命令不支持/参数不合法 |
+/// | 401  | 开启认证之后还没有认证, 或者用户名密码错误 |
+/// | 403  | follower上不能执行修改, 或者用户没有这个key的权限 |
+/// | 404  | key或者订阅不存在 |
+/// | 409  | cas/put_if_absent/txn的条件不满足 |
+/// | 421  | 集群模式下当前节点不是leader, message中带有leader的地址 |
+/// | 429  | 客户端的请求超过了限流 |
+/// | 500  | 存储或者服务端内部错误 |
+/// | 503  | 代理连接不上后端 |
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("cannot decode request: {0}")]
+    Decode(String),
+    #[error("invalid command: {0}")]
+    InvalidCommand(String),
+    #[error("unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("read only: {0}")]
+    ReadOnly(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("condition not met: {0}")]
+    Conflict(String),
+    #[error("not leader, leader is {0}")]
+    NotLeader(String),
+    #[error("too many requests: {0}")]
+    RateLimited(String),
+    #[error("storage error: {0}")]
+    Storage(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl KvError {
+    pub fn code(&self) -> u32 {
+        match self {
+            KvError::Decode(_) | KvError::InvalidCommand(_) => 400,
+            KvError::Unauthenticated(_) => 401,
+            KvError::ReadOnly(_) | KvError::PermissionDenied(_) => 403,
+            KvError::NotFound(_) => 404,
+            KvError::Conflict(_) => 409,
+            KvError::NotLeader(_) => 421,
+            KvError::RateLimited(_) => 429,
+            KvError::Storage(_) | KvError::Internal(_) => 500,
+            KvError::Unavailable(_) => 503,
+        }
+    }
+}
+
+impl From<prost::DecodeError> for KvError {
+    fn from(e: prost::DecodeError) -> Self {
+        KvError::Decode(e.to_string())
+    }
+}
+
+// Storage返回的是anyhow::Error
+impl From<anyhow::Error> for KvError {
+    fn from(e: anyhow::Error) -> Self {
+        KvError::Storage(e.to_string())
+    }
+}
+
+impl From<KvError> for Response {
+    fn from(e: KvError) -> Self {
+        Response {
+            code: e.code(),
+            message: e.to_string(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, TypedHeader};
+use axum::headers::authorization::{Authorization, Basic};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+use tower_http::limit::RequestBodyLimitLayer;
+use tracing::debug;
+use crate::error::KvError;
+use crate::limit::{idle, ConnectionLimits};
+use crate::protobuf::{Request, Response};
+use crate::server::execute;
+use crate::service::ServerState;
+
+/// HTTP网关, 和TCP协议共用同一个ServerState
+///
+/// - `GET /kv/{key}` 返回原始的value
+/// - `PUT /kv/{key}?ttl=毫秒` 请求体作为value写入
+/// - `DELETE /kv/{key}`
+/// - `GET /kv?prefix=user:` 按前缀列出key
+///
+/// 开启认证时需要HTTP Basic认证, 用户名和密码与Auth命令相同, 每个请求按这个用户的权限检查
+///
+/// 错误返回json `{"code": 404, "message": "not found: key"}`, http状态码和code一致,
+/// 请求体超过max_body_size(和max_message_size一致)时返回413
+pub fn router(state: Arc<ServerState>, max_body_size: usize) -> Router {
+    Router::new()
+        .route("/kv", get(list))
+        .route("/kv/*key", get(get_key).put(put_key).delete(delete_key))
+        .layer(Extension(state))
+        .layer(RequestBodyLimitLayer::new(max_body_size))
+}
+
+/// 处理一个HTTP连接, 和kv协议的连接一样受Limiter的限制, 超过限流的请求返回429,
+/// 没有请求在处理并且超过空闲时间时关闭连接, 服务端退出时处理完正在执行的请求之后关闭
+pub async fn serve_http<T>(stream: T, state: Arc<ServerState>, router: Router, limits: ConnectionLimits) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let _connection = state.metrics().connection("http");
+    let mut shutdown = state.shutdown_signal();
+    let limits = Arc::new(limits);
+    let activity = Arc::new(Mutex::new(Activity { in_flight: 0, last: Instant::now() }));
+    let service = {
+        let (limits, activity) = (limits.clone(), activity.clone());
+        service_fn(move |request| {
+            let mut router = router.clone();
+            let allowed = limits.allow();
+            let in_flight = InFlight::start(&activity);
+            async move {
+                let _in_flight = in_flight;
+                if !allowed {
+                    return Ok(HttpError { code: 429, message: "rate limit exceeded".into() }.into_response());
+                }
+                router.call(request).await
+            }
+        })
+    };
+    let connection = Http::new().serve_connection(stream, service);
+    tokio::pin!(connection);
+    let mut deadline = limits.idle_timeout.map(|timeout| Instant::now() + timeout);
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            result = &mut connection => return Ok(result?),
+            _ = shutdown.recv(), if !closing => {
+                closing = true;
+                connection.as_mut().graceful_shutdown();
+            }
+            _ = idle(deadline), if !closing => {
+                let (in_flight, last) = {
+                    let activity = activity.lock().unwrap_or_else(|e| e.into_inner());
+                    (activity.in_flight, activity.last)
+                };
+                // 还有请求在处理时不算空闲
+                let now = Instant::now();
+                deadline = limits.idle_timeout.map(|timeout| if in_flight > 0 { now + timeout } else { last + timeout });
+                if in_flight == 0 && deadline <= Some(now) {
+                    debug!("http connection idle for {:?}, closing", limits.idle_timeout);
+                    closing = true;
+                    connection.as_mut().graceful_shutdown();
+                }
+            }
+        }
+    }
+}
+
+// 连接上正在处理的请求数和最后一次请求结束的时间
+#[derive(Debug)]
+struct Activity {
+    in_flight: usize,
+    last: Instant,
+}
+
+// 请求处理完或者被取消时drop, 更新Activity
+struct InFlight(Arc<Mutex<Activity>>);
+
+impl InFlight {
+    fn start(activity: &Arc<Mutex<Activity>>) -> Self {
+        activity.lock().unwrap_or_else(|e| e.into_inner()).in_flight += 1;
+        InFlight(activity.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut activity = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        activity.in_flight -= 1;
+        activity.last = Instant::now();
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PutParams {
+    #[serde(default)]
+    ttl: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyList {
+    keys: Vec<String>,
+}
+
+// kv返回的错误, 转换成json
+#[derive(Debug, Serialize)]
+struct HttpError {
+    code: u32,
+    message: String,
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self)).into_response()
+    }
+}
+
+fn check(response: Response) -> Result<Response, HttpError> {
+    match response.code {
+        0 => Ok(response),
+        code => Err(HttpError { code, message: response.message }),
+    }
+}
+
+type Credentials = Option<TypedHeader<Authorization<Basic>>>;
+
+// 开启认证时执行之前先检查用户名密码和权限
+async fn execute_as(state: &ServerState, credentials: Credentials, request: Request) -> Response {
+    if let Some(acl) = state.acl() {
+        let user = credentials.and_then(|TypedHeader(Authorization(basic))| acl.authenticate(basic.username(), basic.password()));
+        let result = match user {
+            Some(user) => user.check(&request),
+            None => Err(KvError::Unauthenticated("authentication required".into())),
+        };
+        if let Err(e) = result {
+            return e.into();
+        }
+    }
+    execute(state, request).await
+}
+
+// 通配符匹配到的路径可能带有开头的/
+fn key(path: &str) -> &str {
+    path.strip_prefix('/').unwrap_or(path)
+}
+
+async fn list(
+    Extension(state): Extension<Arc<ServerState>>,
+    credentials: Credentials,
+    Query(params): Query<ListParams>,
+) -> Result<Json<KeyList>, HttpError> {
+    let response = check(execute_as(&state, credentials, Request::new_keys(&params.prefix)).await)?;
+    Ok(Json(KeyList { keys: response.pairs.into_iter().map(|p| p.key).collect() }))
+}
+
+async fn get_key(
+    Extension(state): Extension<Arc<ServerState>>,
+    credentials: Credentials,
+    Path(path): Path<String>,
+) -> Result<HttpResponse, HttpError> {
+    let response = check(execute_as(&state, credentials, Request::new_get(key(&path))).await)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], response.value).into_response())
+}
+
+async fn put_key(
+    Extension(state): Extension<Arc<ServerState>>,
+    credentials: Credentials,
+    Path(path): Path<String>,
+    Query(params): Query<PutParams>,
+    body: Bytes,
+) -> Result<StatusCode, HttpError> {
+    check(execute_as(&state, credentials, Request::new_put_ex(key(&path), &body, params.ttl)).await)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_key(
+    Extension(state): Extension<Arc<ServerState>>,
+    credentials: Credentials,
+    Path(path): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    check(execute_as(&state, credentials, Request::new_delete(key(&path))).await)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use axum::body::Body;
+    use axum::http::Request as HttpRequest;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tower::ServiceExt;
+    use crate::acl::Acl;
+    use crate::config::{AuthConfig, RateLimitConfig, ServerConfig, UserConfig};
+    use crate::limit::Limiter;
+    use super::*;
+
+    async fn call(router: &Router, method: &str, uri: &str, body: impl Into<Body>) -> (StatusCode, Vec<u8>) {
+        call_as(router, None, method, uri, body).await
+    }
+
+    // credentials是base64编码之后的"用户名:密码"
+    async fn call_as(router: &Router, credentials: Option<&str>, method: &str, uri: &str, body: impl Into<Body>) -> (StatusCode, Vec<u8>) {
+        let mut request = HttpRequest::builder().method(method).uri(uri);
+        if let Some(credentials) = credentials {
+            request = request.header(header::AUTHORIZATION, format!("Basic {}", credentials));
+        }
+        let response = router.clone().oneshot(request.body(body.into()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_http_gateway() {
+        let router = router(Arc::new(ServerState::default()), 1024);
+        assert_eq!(call(&router, "PUT", "/kv/user/1", "alice").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(call(&router, "PUT", "/kv/user/2?ttl=60000", "bob").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(call(&router, "GET", "/kv/user/1", "").await, (StatusCode::OK, b"alice".to_vec()));
+        assert_eq!(call(&router, "GET", "/kv?prefix=user/", "").await, (StatusCode::OK, br#"{"keys":["user/1","user/2"]}"#.to_vec()));
+
+        assert_eq!(call(&router, "DELETE", "/kv/user/1", "").await.0, StatusCode::NO_CONTENT);
+        let (status, body) = call(&router, "GET", "/kv/user/1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, br#"{"code":404,"message":"not found: user/1"}"#.to_vec());
+
+        // 超过max_message_size的请求体
+        assert_eq!(call(&router, "PUT", "/kv/large", "v".repeat(1025)).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(call(&router, "GET", "/kv/large", "").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_http_acl() {
+        let auth = AuthConfig {
+            users: vec![
+                UserConfig { name: "writer".into(), password: Some("secret".into()), write: vec!["a/".into()], ..Default::default() },
+                UserConfig { name: "reader".into(), password: Some("readonly".into()), read: vec!["a/".into()], ..Default::default() },
+            ],
+        };
+        let router = router(Arc::new(ServerState::default().with_acl(Acl::new(&auth).unwrap())), 1024);
+        // writer:secret, reader:readonly, writer:wrong
+        let (writer, reader, wrong) = ("d3JpdGVyOnNlY3JldA==", "cmVhZGVyOnJlYWRvbmx5", "d3JpdGVyOndyb25n");
+
+        // 没有认证信息或者密码错误
+        assert_eq!(call(&router, "GET", "/kv/a/1", "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "PUT", "/kv/a/1", "x").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call_as(&router, Some(wrong), "GET", "/kv/a/1", "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call_as(&router, Some(wrong), "DELETE", "/kv/a/1", "").await.0, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(call_as(&router, Some(writer), "PUT", "/kv/a/1", "x").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(call_as(&router, Some(writer), "PUT", "/kv/b/1", "x").await.0, StatusCode::FORBIDDEN);
+        // 只读的用户可以读, 不能写和删除
+        assert_eq!(call_as(&router, Some(reader), "GET", "/kv/a/1", "").await, (StatusCode::OK, b"x".to_vec()));
+        assert_eq!(call_as(&router, Some(reader), "PUT", "/kv/a/1", "y").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call_as(&router, Some(reader), "DELETE", "/kv/a/1", "").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call_as(&router, Some(writer), "GET", "/kv/a/1", "").await, (StatusCode::OK, b"x".to_vec()));
+    }
+
+    // 读取一个完整的HTTP响应, 返回状态行
+    async fn read_response(stream: &mut DuplexStream) -> String {
+        let mut buf = Vec::new();
+        loop {
+            let mut chunk = [0u8; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text.lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map(|len| len.parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if buf.len() >= end + 4 + length {
+                    return text.lines().next().unwrap().to_owned();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_rate_limit_and_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: 1,
+            rate_limit: Some(RateLimitConfig { requests_per_second: 1, burst: None }),
+            ..Default::default()
+        };
+        let limits = Limiter::new(&config).admit("127.0.0.1".parse().unwrap()).unwrap();
+        let state = Arc::new(ServerState::default());
+        let (mut client, server) = duplex(4096);
+        let server = tokio::spawn(serve_http(server, state.clone(), router(state, 1024), limits));
+
+        let request = b"GET /kv/missing HTTP/1.1\r\nhost: localhost\r\n\r\n";
+        client.write_all(request).await.unwrap();
+        assert_eq!(read_response(&mut client).await, "HTTP/1.1 404 Not Found");
+        client.write_all(request).await.unwrap();
+        assert_eq!(read_response(&mut client).await, "HTTP/1.1 429 Too Many Requests");
+        // 空闲超过1秒之后关闭连接
+        tokio::time::timeout(Duration::from_secs(3), server).await.unwrap().unwrap().unwrap();
+        assert_eq!(client.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,21 @@
+pub mod error;
+pub mod protobuf;
+pub mod noise_codec;
+pub mod storage;
+pub mod service;
+pub mod topic;
+pub mod ttl;
+pub mod server;
+pub mod client;
+pub mod config;
+pub mod wal;
+pub mod replication;
+pub mod raft;
+pub mod proxy;
+pub mod resp;
+pub mod http;
+pub mod limit;
+pub mod metrics;
+pub mod compression;
+pub mod tls;
+pub mod acl;
@@ -0,0 +1,159 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::config::ServerConfig;
+
+// 令牌桶超过这个数量时清理没有连接并且已经补满的桶
+const BUCKET_CLEANUP_THRESHOLD: usize = 1024;
+
+/// 令牌桶, 每秒补充rate个令牌, 最多存burst个, 每个请求消耗一个
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    // 剩余的令牌和上次补充的时间
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// 取一个令牌, 没有令牌时返回false
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.refill();
+        if state.0 < 1.0 {
+            return false;
+        }
+        state.0 -= 1.0;
+        true
+    }
+
+    fn is_full(&self) -> bool {
+        self.refill().0 >= self.burst
+    }
+
+    fn refill(&self) -> std::sync::MutexGuard<'_, (f64, Instant)> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.burst);
+        state.1 = now;
+        state
+    }
+}
+
+/// 一个连接上的限制, 由Limiter::admit创建, 连接关闭时drop
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+    /// 超过这个时间没有收发数据时断开
+    pub idle_timeout: Option<Duration>,
+    /// 同一个客户端地址的所有连接共用的令牌桶
+    pub bucket: Option<Arc<TokenBucket>>,
+    // 占用的连接数, drop之后其它连接才能进来
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimits {
+    /// 没有令牌时返回false, 这个请求应该被拒绝
+    pub fn allow(&self) -> bool {
+        self.bucket.as_ref().map(|bucket| bucket.try_acquire()).unwrap_or(true)
+    }
+}
+
+/// 服务端的连接数, 空闲超时和按客户端地址的限流
+#[derive(Debug)]
+pub struct Limiter {
+    connections: Option<Arc<Semaphore>>,
+    idle_timeout: Option<Duration>,
+    // 每秒的令牌数和桶的容量
+    rate: Option<(u32, u32)>,
+    buckets: DashMap<IpAddr, Arc<TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(config: &ServerConfig) -> Self {
+        Limiter {
+            connections: match config.max_connections {
+                0 => None,
+                max => Some(Arc::new(Semaphore::new(max))),
+            },
+            idle_timeout: match config.idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            rate: config.rate_limit.as_ref()
+                .map(|limit| (limit.requests_per_second, limit.burst.unwrap_or(limit.requests_per_second))),
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// 接受一个新的连接, 超过最大连接数时返回None
+    pub fn admit(&self, addr: IpAddr) -> Option<ConnectionLimits> {
+        let permit = match &self.connections {
+            Some(connections) => Some(connections.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        let bucket = self.rate.map(|(rate, burst)| {
+            if self.buckets.len() >= BUCKET_CLEANUP_THRESHOLD {
+                // 没有连接在用并且已经补满的桶和新建的桶没有区别
+                self.buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.is_full());
+            }
+            self.buckets.entry(addr).or_insert_with(|| Arc::new(TokenBucket::new(rate, burst))).clone()
+        });
+        Some(ConnectionLimits { idle_timeout: self.idle_timeout, bucket, _permit: permit })
+    }
+}
+
+/// 等待到deadline, 没有deadline时一直等待
+pub async fn idle(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RateLimitConfig;
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(10, 2);
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn test_limiter() {
+        let config = ServerConfig {
+            max_connections: 2,
+            rate_limit: Some(RateLimitConfig { requests_per_second: 1, burst: None }),
+            ..Default::default()
+        };
+        let limiter = Limiter::new(&config);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let a = limiter.admit(ip).unwrap();
+        let b = limiter.admit(ip).unwrap();
+        assert!(limiter.admit("10.0.0.2".parse().unwrap()).is_none());
+        // 同一个地址的连接共用令牌
+        assert!(a.allow());
+        assert!(!b.allow());
+        drop(a);
+        let c = limiter.admit("10.0.0.2".parse().unwrap()).unwrap();
+        assert!(c.allow());
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::time::Duration;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Router};
+use prometheus::{exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use crate::service::ServerState;
+
+/// 服务端的监控指标, 通过 `GET /metrics` 以prometheus的文本格式输出
+///
+/// - `kv_requests_total{command, code}` 请求数, code是响应的code, 0表示成功
+/// - `kv_request_duration_seconds{command}` 请求的处理时间
+/// - `kv_keys` key的数量
+/// - `kv_memory_bytes` 所有key和value的字节数, 用来估计占用的内存
+/// - `kv_connections{protocol}` 当前的连接数, protocol是kv或者resp
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    keys: IntGauge,
+    memory: IntGauge,
+    connections: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("kv_requests_total", "number of requests by command and response code"),
+            &["command", "code"],
+        ).unwrap();
+        // 从50微秒开始, 最大的桶大约1.6秒
+        let latency = HistogramVec::new(
+            HistogramOpts::new("kv_request_duration_seconds", "request latency by command")
+                .buckets(exponential_buckets(0.00005, 2.0, 16).unwrap()),
+            &["command"],
+        ).unwrap();
+        let keys = IntGauge::new("kv_keys", "number of keys").unwrap();
+        let memory = IntGauge::new("kv_memory_bytes", "estimated size of all keys and values in bytes").unwrap();
+        let connections = IntGaugeVec::new(
+            Opts::new("kv_connections", "number of active connections by protocol"),
+            &["protocol"],
+        ).unwrap();
+        let registry = Registry::new();
+        // 名字都是固定的, 注册不会失败
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(keys.clone())).unwrap();
+        registry.register(Box::new(memory.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        Metrics { registry, requests, latency, keys, memory, connections }
+    }
+
+    /// 记录一个请求的结果和耗时
+    pub fn observe(&self, command: &str, code: u32, elapsed: Duration) {
+        self.requests.with_label_values(&[command, &code.to_string()]).inc();
+        self.latency.with_label_values(&[command]).observe(elapsed.as_secs_f64());
+    }
+
+    /// 连接建立时调用, 返回的guard在连接关闭时drop
+    pub fn connection(&self, protocol: &str) -> ConnectionGuard {
+        let gauge = self.connections.with_label_values(&[protocol]);
+        gauge.inc();
+        ConnectionGuard(gauge)
+    }
+
+    /// 写入之后key和数据大小的变化
+    pub(crate) fn add_data(&self, keys: i64, bytes: i64) {
+        self.keys.add(keys);
+        self.memory.add(bytes);
+    }
+
+    pub fn keys(&self) -> i64 {
+        self.keys.get()
+    }
+
+    pub fn memory_bytes(&self) -> i64 {
+        self.memory.get()
+    }
+
+    /// prometheus的文本格式
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        // 写入Vec不会失败
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 连接数的计数, drop时减一
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// 只有 `GET /metrics` 的路由
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .layer(Extension(state))
+}
+
+async fn metrics(Extension(state): Extension<Arc<ServerState>>) -> impl IntoResponse {
+    (StatusCode::OK, [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], state.metrics().encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_metrics() {
+        let metrics = Metrics::new();
+        metrics.observe("get", 0, Duration::from_micros(80));
+        metrics.observe("get", 404, Duration::from_micros(60));
+        metrics.add_data(2, 10);
+        let guard = metrics.connection("kv");
+        let text = metrics.encode();
+        assert!(text.contains("kv_requests_total{code=\"404\",command=\"get\"} 1"), "{}", text);
+        assert!(text.contains("kv_request_duration_seconds_count{command=\"get\"} 2"), "{}", text);
+        assert!(text.contains("kv_keys 2"));
+        assert!(text.contains("kv_memory_bytes 10"));
+        assert!(text.contains("kv_connections{protocol=\"kv\"} 1"));
+        drop(guard);
+        assert!(metrics.encode().contains("kv_connections{protocol=\"kv\"} 0"));
+    }
+}
@@ -1,16 +1,35 @@
-use anyhow::Result;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use snow::{HandshakeState, TransportState};
+use futures::{SinkExt, StreamExt};
+use snow::{HandshakeState, Keypair, TransportState};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use crate::compression::{self, LZ4};
 
 pub const NOISE_CODEC: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
 pub const HEADER_LEN: usize = 2;
 pub const MAX_FRAME_LEN: usize = 65535;
+// ChaChaPoly每个消息都会带上16字节的认证tag
+const TAG_LEN: usize = 16;
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - TAG_LEN;
+/// 一个消息重组之后的最大字节数, 超过一帧的消息会拆成多帧发送
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+// 握手之后每一帧明文的第一个字节, 最低位表示后面是否还有帧, 第二位表示消息是否压缩过
+const FLAG_LAST: u8 = 0;
+const FLAG_MORE: u8 = 1;
+const FLAG_COMPRESSED: u8 = 2;
 
+#[derive(Debug, Clone)]
 pub struct Builder {
     params: &'static str,
     initiator: bool,
+    local_private_key: Option<Vec<u8>>,
+    remote_public_key: Option<Vec<u8>>,
+    max_frame_len: usize,
+    max_message_len: usize,
+    compression: bool,
 }
 
 impl Builder {
@@ -18,20 +37,77 @@ impl Builder {
         Builder {
             params,
             initiator,
+            local_private_key: None,
+            remote_public_key: None,
+            max_frame_len: MAX_FRAME_LEN,
+            max_message_len: MAX_MESSAGE_LEN,
+            compression: false,
         }
     }
 
+    /// 使用固定的私钥, 不设置时每次都会生成一个临时的密钥对
+    pub fn local_private_key(mut self, key: Vec<u8>) -> Self {
+        self.local_private_key = Some(key);
+        self
+    }
+
+    /// 固定对端的公钥, 握手完成后对端的公钥不一致时会报错
+    pub fn remote_public_key(mut self, key: Vec<u8>) -> Self {
+        self.remote_public_key = Some(key);
+        self
+    }
+
+    /// 固定的对端公钥
+    pub fn pinned_public_key(&self) -> Option<&[u8]> {
+        self.remote_public_key.as_deref()
+    }
+
+    /// 限制单个加密帧的大小, 不能超过MAX_FRAME_LEN
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        // 除了tag和标记之外至少还要能放下一个字节
+        self.max_frame_len = len.clamp(TAG_LEN + 2, MAX_FRAME_LEN);
+        self
+    }
+
+    /// 限制多帧重组之后的消息大小, 超过时连接会断开
+    pub fn max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len.max(1);
+        self
+    }
+
+    /// 是否支持lz4压缩, 握手时双方都支持才会压缩之后的消息
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// 从文件加载本端私钥和需要固定的对端公钥
+    pub fn key_files(mut self, private: Option<&str>, remote_public: Option<&str>) -> Result<Self> {
+        if let Some(path) = private {
+            self = self.local_private_key(load_key(path)?);
+        }
+        if let Some(path) = remote_public {
+            self = self.remote_public_key(load_key(path)?);
+        }
+        Ok(self)
+    }
+
     pub fn new_codec(self) -> Result<NoiseCodec> {
         let builder = snow::Builder::new(self.params.parse()?);
-        let keypair = builder.generate_keypair()?;
-        let mut builder = builder.local_private_key(&keypair.private);
+        let private = match &self.local_private_key {
+            Some(key) => key.clone(),
+            None => builder.generate_keypair()?.private,
+        };
+        let builder = builder.local_private_key(&private);
         let noise = match self.initiator {
             true => builder.build_initiator()?,
             false => builder.build_responder()?
         };
         Ok(NoiseCodec {
             builder: self,
-            state: NoiseState::Handshake(noise),
+            state: Some(NoiseState::Handshake(Box::new(noise))),
+            partial: BytesMut::new(),
+            compression: false,
         })
     }
 
@@ -43,8 +119,63 @@ impl Builder {
     }
 }
 
+/// 生成一个新的静态密钥对
+pub fn generate_keypair() -> Result<Keypair> {
+    Ok(snow::Builder::new(NOISE_CODEC.parse()?).generate_keypair()?)
+}
+
+/// 从文件读取密钥, 文件内容是32字节的原始密钥
+pub fn load_key(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    let key = fs::read(path)?;
+    if key.len() != 32 {
+        return Err(anyhow!("invalid key file {:?}: expect 32 bytes, got {}", path, key.len()));
+    }
+    Ok(key)
+}
+
+/// 完成noise握手, 之后的消息都会被加密
+/// XX模式一共三次消息: initiator -> e, responder -> e, ee, s, es, initiator -> s, se
+/// 压缩算法也在握手时协商, 只使用加密并且认证过的第二和第三个消息, 中间人不能篡改:
+/// responder在第二个消息中带上支持的算法(逗号分隔), initiator在第三个消息中带上选中的算法,
+/// 没有带的一方不压缩, 所以和不支持压缩的对端也能握手
+pub async fn handshake<T>(framed: &mut Framed<T, NoiseCodec>) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let initiator = framed.codec().builder.initiator;
+    let enabled = framed.codec().builder.compression;
+    for step in 0..3 {
+        // initiator在第0和第2步发送, responder在第1步发送
+        if (step % 2 == 0) == initiator {
+            let payload = match step {
+                1 if enabled => Bytes::from_static(LZ4),
+                2 if framed.codec().compression => Bytes::from_static(LZ4),
+                _ => Bytes::new(),
+            };
+            framed.send(payload).await?;
+        } else {
+            let payload = framed.next().await.ok_or_else(|| anyhow!("connection closed during handshake"))??;
+            match step {
+                1 => framed.codec_mut().compression = enabled && payload[..].split(|&c| c == b',').any(|name| name == LZ4),
+                2 => framed.codec_mut().compression = enabled && &payload[..] == LZ4,
+                _ => {}
+            }
+        }
+    }
+    let codec = framed.codec();
+    if !codec.is_transport() {
+        return Err(anyhow!("noise handshake not finished"));
+    }
+    if let Some(expected) = &codec.builder.remote_public_key {
+        if codec.remote_static() != Some(expected.as_slice()) {
+            return Err(anyhow!("remote public key mismatch"));
+        }
+    }
+    Ok(())
+}
+
 enum NoiseState {
-    Handshake(HandshakeState),
+    Handshake(Box<HandshakeState>),
     Transport(TransportState),
 }
 
@@ -52,11 +183,11 @@ impl NoiseState {
     fn write_message(&mut self, message: &[u8], output: &mut [u8]) -> Result<usize> {
         match self {
             NoiseState::Handshake(state) => {
-                let (len, _) = state.write_message(message, output)?;
+                let len = state.write_message(message, output)?;
                 Ok(len)
             }
             NoiseState::Transport(state) => {
-                let (len, _) = state.write_message(message, output)?;
+                let len = state.write_message(message, output)?;
                 Ok(len)
             }
         }
@@ -65,34 +196,138 @@ impl NoiseState {
     fn read_message(&mut self, message: &[u8], output: &mut [u8]) -> Result<usize> {
         match self {
             NoiseState::Handshake(state) => {
-                let (len, _) = state.read_message(message, output)?;
+                let len = state.read_message(message, output)?;
                 Ok(len)
             }
             NoiseState::Transport(state) => {
-                let (len, _) = state.read_message(message, output)?;
+                let len = state.read_message(message, output)?;
                 Ok(len)
             }
         }
     }
+
+    // 握手完成后切换到传输状态
+    fn transition(self) -> Result<Self> {
+        match self {
+            NoiseState::Handshake(state) if state.is_handshake_finished() => {
+                Ok(NoiseState::Transport(state.into_transport_mode()?))
+            }
+            state => Ok(state),
+        }
+    }
+
+    fn remote_static(&self) -> Option<&[u8]> {
+        match self {
+            NoiseState::Handshake(state) => state.get_remote_static(),
+            NoiseState::Transport(state) => state.get_remote_static(),
+        }
+    }
 }
 
+/// noise加密的帧, 每一帧是2字节的长度加上密文
+/// 握手之后明文的第一个字节是标记, 超过一帧的消息拆成多帧发送, 最后一帧之前的标记都是FLAG_MORE,
+/// 接收方收到最后一帧之后把所有帧拼成一个完整的消息, 压缩过的消息拼好之后再解压
 pub struct NoiseCodec {
     builder: Builder,
-    state: NoiseState,
+    // 只有在状态切换失败后才会是None
+    state: Option<NoiseState>,
+    // 还没有收到最后一帧的消息
+    partial: BytesMut,
+    // 握手时协商的结果
+    compression: bool,
+}
+
+impl NoiseCodec {
+    pub fn is_transport(&self) -> bool {
+        matches!(self.state, Some(NoiseState::Transport(_)))
+    }
+
+    /// 握手之后是否压缩消息
+    pub fn is_compressed(&self) -> bool {
+        self.compression
+    }
+
+    /// 对端的静态公钥, 在握手的过程中才会拿到
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.state.as_ref()?.remote_static()
+    }
+
+    fn state(&mut self) -> Result<&mut NoiseState> {
+        self.state.as_mut().ok_or_else(|| anyhow!("noise state is broken"))
+    }
+
+    fn transition(&mut self) -> Result<()> {
+        if let Some(state) = self.state.take() {
+            self.state = Some(state.transition()?);
+        }
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<()> {
+        if item.len() > self.builder.max_frame_len - TAG_LEN {
+            return Err(anyhow!("frame too large"));
+        }
+        let mut body = vec![0u8; self.builder.max_frame_len];
+        let n = self.state()?.write_message(item, &mut body)?;
+        self.transition()?;
+        dst.reserve(HEADER_LEN + n);
+        dst.put_uint(n as u64, HEADER_LEN);
+        dst.put_slice(&body[..n]);
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        // 先看长度, 数据不够时不能消费掉头部
+        let len = (&src[..HEADER_LEN]).get_uint(HEADER_LEN) as usize;
+        if len > self.builder.max_frame_len {
+            return Err(anyhow!("frame too large: {}", len));
+        }
+        if src.len() < (HEADER_LEN + len) {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let payload = src.split_to(len);
+        let mut decode = BytesMut::zeroed(len);
+        let n = self.state()?.read_message(&payload, &mut decode)?;
+        self.transition()?;
+        decode.truncate(n);
+        Ok(Some(decode))
+    }
 }
 
 impl Encoder<Bytes> for NoiseCodec{
     type Error = anyhow::Error;
 
     fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> std::result::Result<(), Self::Error> {
-        if &item.len() > &MAX_FRAME_LEN {
-            return Err(anyhow::anyhow!("frame too large"));
+        // 握手的消息不分帧
+        if !self.is_transport() {
+            return self.encode_frame(&item, dst);
+        }
+        if item.len() > self.builder.max_message_len {
+            return Err(anyhow!("message too large: {}", item.len()));
+        }
+        // 压缩之后没有变小的消息原样发送
+        let (item, compressed) = match self.compression {
+            true => match compression::compress_if_smaller(&item) {
+                Some(data) => (Bytes::from(data), FLAG_COMPRESSED),
+                None => (item, FLAG_LAST),
+            },
+            false => (item, FLAG_LAST),
+        };
+        let chunk_len = self.builder.max_frame_len - TAG_LEN - 1;
+        let count = item.len().div_ceil(chunk_len).max(1);
+        let mut frame = Vec::with_capacity(chunk_len.min(item.len()) + 1);
+        for i in 0..count {
+            let chunk = &item[i * chunk_len..item.len().min((i + 1) * chunk_len)];
+            frame.clear();
+            frame.push(compressed | if i + 1 == count { FLAG_LAST } else { FLAG_MORE });
+            frame.extend_from_slice(chunk);
+            self.encode_frame(&frame, dst)?;
         }
-        dst.reserve(HEADER_LEN + &item.len() * 2);
-        let mut body = dst.split_off(2);
-        let n = self.state.write_message(&item, &mut body)?;
-        dst.put_uint(n as u64, HEADER_LEN);
-        dst.unsplit(body);
         Ok(())
     }
 }
@@ -103,17 +338,138 @@ impl Decoder for NoiseCodec{
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> std::result::Result<Option<Self::Item>, Self::Error> {
-        if src.len() < HEADER_LEN {
-            return Ok(None);
-        }
-        let len = src.get_uint(HEADER_LEN) as usize;
-        if src.len() < (HEADER_LEN + &len) {
-            return Ok(None);
+        loop {
+            // 解密之后可能切换到传输状态, 需要先记下来
+            let transport = self.is_transport();
+            let mut frame = match self.decode_frame(src)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            if !transport {
+                return Ok(Some(frame));
+            }
+            if frame.is_empty() {
+                return Err(anyhow!("frame without flag"));
+            }
+            let flag = frame[0];
+            if flag & !(FLAG_MORE | FLAG_COMPRESSED) != 0 {
+                return Err(anyhow!("invalid frame flag: {}", flag));
+            }
+            frame.advance(1);
+            if self.partial.len() + frame.len() > self.builder.max_message_len {
+                return Err(anyhow!("message too large: {}", self.partial.len() + frame.len()));
+            }
+            if flag & FLAG_MORE != 0 {
+                self.partial.extend_from_slice(&frame);
+                continue;
+            }
+            let message = match self.partial.is_empty() {
+                true => frame,
+                false => {
+                    self.partial.extend_from_slice(&frame);
+                    self.partial.split()
+                }
+            };
+            if flag & FLAG_COMPRESSED == 0 {
+                return Ok(Some(message));
+            }
+            if !self.compression {
+                return Err(anyhow!("compression not negotiated"));
+            }
+            let message = compression::decompress(&message, self.builder.max_message_len)?;
+            return Ok(Some(BytesMut::from(&message[..])));
         }
-        let mut payload = src.split_to(len);
-        let n = self.state.read_message(&payload, src)?;
-        let decode = src.split_to(n);
-        Ok(Some(decode))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    async fn connect(client: Builder, server: Builder) -> (Result<Framed<impl AsyncRead + AsyncWrite + Unpin, NoiseCodec>>, Result<Framed<impl AsyncRead + AsyncWrite + Unpin, NoiseCodec>>) {
+        let (a, b) = duplex(1024);
+        let mut client = client.new_framed(a).unwrap();
+        let mut server = server.new_framed(b).unwrap();
+        let (c, s) = tokio::join!(handshake(&mut client), handshake(&mut server));
+        (c.map(|_| client), s.map(|_| server))
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_transport() {
+        let (client, server) = connect(Builder::new(NOISE_CODEC, true), Builder::new(NOISE_CODEC, false)).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        assert!(client.codec().is_transport());
+        client.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(&server.next().await.unwrap().unwrap()[..], b"hello");
+        server.send(Bytes::from_static(b"world")).await.unwrap();
+        assert_eq!(&client.next().await.unwrap().unwrap()[..], b"world");
+    }
+
+    #[tokio::test]
+    async fn test_large_message() {
+        let client = Builder::new(NOISE_CODEC, true).max_frame_len(1024);
+        let server = Builder::new(NOISE_CODEC, false).max_frame_len(1024).max_message_len(10_000);
+        let (client, server) = connect(client, server).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        // 拆成多帧发送, 接收方拼回一个消息
+        let message: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let send = async {
+            client.send(Bytes::from(message.clone())).await.unwrap();
+            client.send(Bytes::new()).await.unwrap();
+            // 超过max_message_len时接收方断开, 发送可能失败
+            let _ = client.send(Bytes::from(vec![0u8; 20_000])).await;
+        };
+        let receive = async {
+            assert_eq!(&server.next().await.unwrap().unwrap()[..], &message[..]);
+            assert!(server.next().await.unwrap().unwrap().is_empty());
+            assert!(server.next().await.unwrap().is_err());
+            drop(server);
+        };
+        tokio::join!(send, receive);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let json = br#"{"name":"kv","tags":["a","b"]}"#.repeat(100);
+        // 只有一方支持时不压缩
+        let (client, server) = connect(Builder::new(NOISE_CODEC, true).compression(true), Builder::new(NOISE_CODEC, false)).await;
+        assert!(!client.unwrap().codec().is_compressed() && !server.unwrap().codec().is_compressed());
+        let (client, server) = connect(Builder::new(NOISE_CODEC, true), Builder::new(NOISE_CODEC, false).compression(true)).await;
+        assert!(!client.unwrap().codec().is_compressed() && !server.unwrap().codec().is_compressed());
+
+        let client = Builder::new(NOISE_CODEC, true).compression(true).max_frame_len(1024);
+        let server = Builder::new(NOISE_CODEC, false).compression(true).max_frame_len(1024);
+        let (client, server) = connect(client, server).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        assert!(client.codec().is_compressed() && server.codec().is_compressed());
+        let send = async {
+            client.send(Bytes::from(json.clone())).await.unwrap();
+            client.send(Bytes::from_static(b"short")).await.unwrap();
+        };
+        let receive = async {
+            assert_eq!(&server.next().await.unwrap().unwrap()[..], &json[..]);
+            assert_eq!(&server.next().await.unwrap().unwrap()[..], b"short");
+        };
+        tokio::join!(send, receive);
+        // 压缩之后只需要一帧
+        let mut dst = BytesMut::new();
+        server.codec_mut().encode(Bytes::from(json.clone()), &mut dst).unwrap();
+        assert!(dst.len() < 1024);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_remote_key() {
+        let server_key = generate_keypair().unwrap();
+        let server = || Builder::new(NOISE_CODEC, false).local_private_key(server_key.private.clone());
+
+        let client = Builder::new(NOISE_CODEC, true).remote_public_key(server_key.public.clone());
+        let (client, server1) = connect(client, server()).await;
+        assert!(client.is_ok() && server1.is_ok());
+
+        let other = generate_keypair().unwrap();
+        let client = Builder::new(NOISE_CODEC, true).remote_public_key(other.public);
+        let (client, _) = connect(client, server()).await;
+        assert!(client.is_err());
+    }
+}
@@ -1,6 +1,9 @@
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Request {
-    #[prost(oneof="request::Command", tags="1, 2")]
+    /// 请求id, 服务端在Response.id中原样返回, 客户端据此匹配响应, 0保留给服务端推送的消息
+    #[prost(uint64, tag="20")]
+    pub id: u64,
+    #[prost(oneof="request::Command", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 21, 22, 23")]
     pub command: ::core::option::Option<request::Command>,
 }
 /// Nested message and enum types in `Request`.
@@ -11,8 +14,49 @@ pub mod request {
         Get(super::RequestGet),
         #[prost(message, tag="2")]
         Put(super::ResponsePut),
+        #[prost(message, tag="3")]
+        Delete(super::RequestDelete),
+        #[prost(message, tag="4")]
+        Exists(super::RequestExists),
+        #[prost(message, tag="5")]
+        Keys(super::RequestKeys),
+        #[prost(message, tag="6")]
+        GetAll(super::RequestGetAll),
+        #[prost(message, tag="7")]
+        PutAll(super::RequestPutAll),
+        #[prost(message, tag="8")]
+        Subscribe(super::RequestSubscribe),
+        #[prost(message, tag="9")]
+        Unsubscribe(super::RequestUnsubscribe),
+        #[prost(message, tag="10")]
+        Publish(super::RequestPublish),
+        #[prost(message, tag="11")]
+        Expire(super::RequestExpire),
+        #[prost(message, tag="12")]
+        Ttl(super::RequestTtl),
+        #[prost(message, tag="13")]
+        Persist(super::RequestPersist),
+        #[prost(message, tag="14")]
+        Replicate(super::RequestReplicate),
+        #[prost(message, tag="15")]
+        Raft(super::RaftMessage),
+        #[prost(message, tag="16")]
+        AddMember(super::RequestAddMember),
+        #[prost(message, tag="17")]
+        RemoveMember(super::RequestRemoveMember),
+        #[prost(message, tag="18")]
+        Cas(super::RequestCompareAndSwap),
+        #[prost(message, tag="19")]
+        PutIfAbsent(super::RequestPutIfAbsent),
+        #[prost(message, tag="21")]
+        Incr(super::RequestIncr),
+        #[prost(message, tag="22")]
+        Txn(super::RequestTxn),
+        #[prost(message, tag="23")]
+        Auth(super::RequestAuth),
     }
 }
+/// code: 0 成功, 400 请求不合法, 401 没有认证, 403 只读或者没有权限, 404 不存在, 409 cas/put_if_absent/txn的条件不满足, 421 不是集群的leader, 429 超过限流, 500 服务端错误, 503 后端不可用, 错误的描述在message中
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Response {
     #[prost(uint32, tag="1")]
@@ -21,6 +65,39 @@ pub struct Response {
     pub key: ::prost::alloc::string::String,
     #[prost(bytes="vec", tag="3")]
     pub value: ::prost::alloc::vec::Vec<u8>,
+    /// 多个key的返回结果, 用于GetAll/Keys
+    #[prost(message, repeated, tag="4")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 订阅成功或者推送消息时带上订阅id
+    #[prost(uint32, tag="5")]
+    pub subscription_id: u32,
+    /// Ttl命令返回的剩余毫秒数, -1表示没有过期时间
+    #[prost(int64, tag="6")]
+    pub ttl: i64,
+    #[prost(string, tag="7")]
+    pub message: ::prost::alloc::string::String,
+    /// 对应的请求id
+    #[prost(uint64, tag="8")]
+    pub id: u64,
+    /// leader推送给follower的修改记录
+    #[prost(message, optional, tag="9")]
+    pub replication: ::core::option::Option<Replication>,
+    /// 集群节点之间的raft消息
+    #[prost(message, optional, tag="10")]
+    pub raft: ::core::option::Option<RaftMessage>,
+    /// Incr之后的值
+    #[prost(int64, tag="11")]
+    pub number: i64,
+    /// Txn中每个操作的结果, 和请求中的操作一一对应
+    #[prost(message, repeated, tag="12")]
+    pub results: ::prost::alloc::vec::Vec<Response>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Kvpair {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RequestGet {
@@ -33,4 +110,376 @@ pub struct ResponsePut {
     pub key: ::prost::alloc::string::String,
     #[prost(bytes="vec", tag="2")]
     pub value: ::prost::alloc::vec::Vec<u8>,
+    /// 过期时间, 单位毫秒, 0表示永不过期
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestDelete {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestExists {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 按前缀列出key, 前缀为空时返回全部
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestKeys {
+    #[prost(string, tag="1")]
+    pub prefix: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestGetAll {
+    #[prost(string, repeated, tag="1")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestPutAll {
+    #[prost(message, repeated, tag="1")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+}
+/// 订阅之后连接会持续收到这个topic上发布的消息
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestSubscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestUnsubscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub id: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestPublish {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+}
+/// 给已存在的key设置过期时间, 单位毫秒
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestExpire {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub ttl: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestTtl {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 去掉key的过期时间
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestPersist {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+}
+/// value等于expected时写入新的值, 不相等时返回409和当前的值, ttl和put一样
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestCompareAndSwap {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub expected: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes="vec", tag="3")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, tag="4")]
+    pub ttl: u64,
+}
+/// key不存在时才写入, 已经存在时返回409和当前的值
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestPutIfAbsent {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+/// 把value当作十进制的整数加上delta, key不存在时从0开始, 保留原来的过期时间
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestIncr {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(sint64, tag="2")]
+    pub delta: i64,
+}
+/// 事务, 在同一把锁里按顺序执行所有操作, 后面的操作能看到前面的修改
+/// 任何一个条件不满足或者出错时所有的修改都不生效, 否则所有的修改作为一条记录写入
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestTxn {
+    #[prost(message, repeated, tag="1")]
+    pub ops: ::prost::alloc::vec::Vec<TxnOp>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TxnOp {
+    #[prost(oneof="txn_op::Op", tags="1, 2, 3, 4, 5")]
+    pub op: ::core::option::Option<txn_op::Op>,
+}
+/// Nested message and enum types in `TxnOp`.
+pub mod txn_op {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        #[prost(message, tag="1")]
+        Get(super::RequestGet),
+        #[prost(message, tag="2")]
+        Check(super::TxnCheck),
+        #[prost(message, tag="3")]
+        Put(super::ResponsePut),
+        #[prost(message, tag="4")]
+        Delete(super::RequestDelete),
+        #[prost(message, tag="5")]
+        Incr(super::RequestIncr),
+    }
+}
+/// 事务的条件, exists检查key是否存在, equals要求key存在并且值相等
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TxnCheck {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(oneof="txn_check::Condition", tags="2, 3")]
+    pub condition: ::core::option::Option<txn_check::Condition>,
+}
+/// Nested message and enum types in `TxnCheck`.
+pub mod txn_check {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Condition {
+        #[prost(bool, tag="2")]
+        Exists(bool),
+        #[prost(bytes, tag="3")]
+        Equals(::prost::alloc::vec::Vec<u8>),
+    }
+}
+/// 用户名和密码认证, 之后这个连接上的请求按这个用户的权限检查
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestAuth {
+    #[prost(string, tag="1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub password: ::prost::alloc::string::String,
+}
+/// 写入wal的修改记录, expire_at是过期的绝对时间(unix毫秒), 0表示永不过期
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogEntry {
+    #[prost(oneof="log_entry::Op", tags="1, 2, 3, 4")]
+    pub op: ::core::option::Option<log_entry::Op>,
+}
+/// Nested message and enum types in `LogEntry`.
+pub mod log_entry {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        #[prost(message, tag="1")]
+        Put(super::LogPut),
+        #[prost(message, tag="2")]
+        Delete(super::LogDelete),
+        #[prost(message, tag="3")]
+        Expire(super::LogExpire),
+        #[prost(message, tag="4")]
+        Batch(super::LogBatch),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogPut {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, tag="3")]
+    pub expire_at: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogDelete {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogExpire {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub expire_at: u64,
+}
+/// 事务的多个修改, 作为一条记录写入和复制
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogBatch {
+    #[prost(message, repeated, tag="1")]
+    pub entries: ::prost::alloc::vec::Vec<LogEntry>,
+}
+/// follower请求从offset之后开始复制, replication_id和leader不一致时从快照开始
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestReplicate {
+    #[prost(string, tag="1")]
+    pub replication_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub offset: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replication {
+    #[prost(string, tag="1")]
+    pub replication_id: ::prost::alloc::string::String,
+    /// 应用完这些记录之后的偏移量
+    #[prost(uint64, tag="2")]
+    pub offset: u64,
+    /// 为true时follower先清空数据, 接下来是leader的快照
+    #[prost(bool, tag="3")]
+    pub reset: bool,
+    #[prost(message, repeated, tag="4")]
+    pub entries: ::prost::alloc::vec::Vec<LogEntry>,
+}
+/// 集群节点之间的消息, 请求和回复都使用这个结构
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMessage {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    /// 发送方的节点id
+    #[prost(uint64, tag="2")]
+    pub from: u64,
+    #[prost(oneof="raft_message::Body", tags="3, 4, 5, 6, 7, 8")]
+    pub body: ::core::option::Option<raft_message::Body>,
+}
+/// Nested message and enum types in `RaftMessage`.
+pub mod raft_message {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag="3")]
+        RequestVote(super::RequestVote),
+        #[prost(message, tag="4")]
+        VoteResponse(super::VoteResponse),
+        #[prost(message, tag="5")]
+        AppendEntries(super::AppendEntries),
+        #[prost(message, tag="6")]
+        AppendResponse(super::AppendResponse),
+        #[prost(message, tag="7")]
+        InstallSnapshot(super::InstallSnapshot),
+        #[prost(message, tag="8")]
+        SnapshotResponse(super::SnapshotResponse),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestVote {
+    #[prost(uint64, tag="1")]
+    pub last_log_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_log_term: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VoteResponse {
+    #[prost(bool, tag="1")]
+    pub granted: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendEntries {
+    #[prost(uint64, tag="1")]
+    pub prev_log_index: u64,
+    #[prost(uint64, tag="2")]
+    pub prev_log_term: u64,
+    #[prost(message, repeated, tag="3")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag="4")]
+    pub leader_commit: u64,
+}
+/// 成功时match_index是和leader一致的最后位置, 失败时leader从match_index + 1开始重试
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendResponse {
+    #[prost(bool, tag="1")]
+    pub success: bool,
+    #[prost(uint64, tag="2")]
+    pub match_index: u64,
+}
+/// 快照分成多条消息发送, offset是这条消息中第一条数据在快照中的序号
+/// 收到done之后follower用快照替换自己的数据, 回复AppendResponse
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallSnapshot {
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_term: u64,
+    #[prost(message, optional, tag="3")]
+    pub membership: ::core::option::Option<Membership>,
+    #[prost(uint64, tag="4")]
+    pub offset: u64,
+    #[prost(message, repeated, tag="5")]
+    pub entries: ::prost::alloc::vec::Vec<LogEntry>,
+    #[prost(bool, tag="6")]
+    pub done: bool,
+}
+/// follower已经收到的快照数据条数, leader从这里继续发送
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotResponse {
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    #[prost(uint64, tag="2")]
+    pub offset: u64,
+}
+/// 日志压缩时保存的快照, 包含last_index以及之前所有记录执行之后的数据
+/// membership为空表示这之前没有成员变更, 使用启动时配置的成员
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshot {
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_term: u64,
+    #[prost(message, optional, tag="3")]
+    pub membership: ::core::option::Option<Membership>,
+    #[prost(message, repeated, tag="4")]
+    pub entries: ::prost::alloc::vec::Vec<LogEntry>,
+}
+/// raft日志的一条记录, data为空的是leader当选时以及清理过期key时写入的空记录
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    /// leader写入时的时间(毫秒), 每个节点都按这个时间计算和判断过期, 执行结果才会一致
+    #[prost(uint64, tag="4")]
+    pub timestamp: u64,
+    #[prost(oneof="raft_entry::Data", tags="2, 3")]
+    pub data: ::core::option::Option<raft_entry::Data>,
+}
+/// Nested message and enum types in `RaftEntry`.
+pub mod raft_entry {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Data {
+        #[prost(message, tag="2")]
+        Request(super::Request),
+        #[prost(message, tag="3")]
+        Membership(super::Membership),
+    }
+}
+/// 集群成员, 写入日志之后立即生效
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Membership {
+    #[prost(message, repeated, tag="1")]
+    pub members: ::prost::alloc::vec::Vec<Member>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Member {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 需要持久化的投票状态
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub voted_for: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestAddMember {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestRemoveMember {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
 }
@@ -3,18 +3,245 @@ mod abi;
 use prost::bytes::{Bytes, BytesMut};
 use prost::Message;
 pub use abi::*;
+use crate::error::KvError;
 
 
 impl Request {
-    pub fn new_get(key: &str) -> Self {
+    fn with_command(command: request::Command) -> Self {
         Request {
-            command: Some(request::Command::Get(RequestGet { key: key.to_owned() }))
+            command: Some(command),
+            id: 0,
+        }
+    }
+
+    /// 设置请求id, 用于匹配响应
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// 是否只读取数据, 同一个连接上连续的只读请求可以并发执行
+    pub fn is_read_only(&self) -> bool {
+        use request::Command::*;
+        match &self.command {
+            Some(Get(_)) | Some(Exists(_)) | Some(Keys(_)) | Some(GetAll(_)) | Some(Ttl(_)) => true,
+            Some(Txn(RequestTxn{ops})) => !ops.iter().any(|op| op.is_write()),
+            _ => false,
+        }
+    }
+
+    /// 命令的名字, 用于监控指标
+    pub fn command_name(&self) -> &'static str {
+        use request::Command::*;
+        match &self.command {
+            Some(Get(_)) => "get",
+            Some(Put(_)) => "put",
+            Some(Delete(_)) => "delete",
+            Some(Exists(_)) => "exists",
+            Some(Keys(_)) => "keys",
+            Some(GetAll(_)) => "get_all",
+            Some(PutAll(_)) => "put_all",
+            Some(Subscribe(_)) => "subscribe",
+            Some(Unsubscribe(_)) => "unsubscribe",
+            Some(Publish(_)) => "publish",
+            Some(Expire(_)) => "expire",
+            Some(Ttl(_)) => "ttl",
+            Some(Persist(_)) => "persist",
+            Some(Replicate(_)) => "replicate",
+            Some(Raft(_)) => "raft",
+            Some(AddMember(_)) => "add_member",
+            Some(RemoveMember(_)) => "remove_member",
+            Some(Cas(_)) => "cas",
+            Some(PutIfAbsent(_)) => "put_if_absent",
+            Some(Incr(_)) => "incr",
+            Some(Txn(_)) => "txn",
+            Some(Auth(_)) => "auth",
+            None => "none",
         }
     }
 
+    pub fn new_get(key: &str) -> Self {
+        Self::with_command(request::Command::Get(RequestGet { key: key.to_owned() }))
+    }
+
     pub fn new_put(key: &str, value: &[u8]) -> Self {
-        Request {
-            command: Some(request::Command::Put(ResponsePut { key: key.to_owned(), value: value.to_vec() }))
+        Self::with_command(request::Command::Put(ResponsePut { key: key.to_owned(), value: value.to_vec(), ttl: 0 }))
+    }
+
+    /// 带过期时间的put, ttl单位毫秒
+    pub fn new_put_ex(key: &str, value: &[u8], ttl: u64) -> Self {
+        Self::with_command(request::Command::Put(ResponsePut { key: key.to_owned(), value: value.to_vec(), ttl }))
+    }
+
+    pub fn new_cas(key: &str, expected: &[u8], value: &[u8], ttl: u64) -> Self {
+        Self::with_command(request::Command::Cas(RequestCompareAndSwap {
+            key: key.to_owned(),
+            expected: expected.to_vec(),
+            value: value.to_vec(),
+            ttl,
+        }))
+    }
+
+    pub fn new_put_if_absent(key: &str, value: &[u8], ttl: u64) -> Self {
+        Self::with_command(request::Command::PutIfAbsent(RequestPutIfAbsent { key: key.to_owned(), value: value.to_vec(), ttl }))
+    }
+
+    /// delta为负数时是减少
+    pub fn new_incr(key: &str, delta: i64) -> Self {
+        Self::with_command(request::Command::Incr(RequestIncr { key: key.to_owned(), delta }))
+    }
+
+    pub fn new_txn(ops: Vec<TxnOp>) -> Self {
+        Self::with_command(request::Command::Txn(RequestTxn { ops }))
+    }
+
+    pub fn new_auth(username: &str, password: &str) -> Self {
+        Self::with_command(request::Command::Auth(RequestAuth { username: username.to_owned(), password: password.to_owned() }))
+    }
+
+    pub fn new_expire(key: &str, ttl: u64) -> Self {
+        Self::with_command(request::Command::Expire(RequestExpire { key: key.to_owned(), ttl }))
+    }
+
+    pub fn new_ttl(key: &str) -> Self {
+        Self::with_command(request::Command::Ttl(RequestTtl { key: key.to_owned() }))
+    }
+
+    pub fn new_persist(key: &str) -> Self {
+        Self::with_command(request::Command::Persist(RequestPersist { key: key.to_owned() }))
+    }
+
+    pub fn new_delete(key: &str) -> Self {
+        Self::with_command(request::Command::Delete(RequestDelete { key: key.to_owned() }))
+    }
+
+    pub fn new_exists(key: &str) -> Self {
+        Self::with_command(request::Command::Exists(RequestExists { key: key.to_owned() }))
+    }
+
+    pub fn new_keys(prefix: &str) -> Self {
+        Self::with_command(request::Command::Keys(RequestKeys { prefix: prefix.to_owned() }))
+    }
+
+    pub fn new_get_all(keys: &[&str]) -> Self {
+        let keys = keys.iter().map(|k| k.to_string()).collect();
+        Self::with_command(request::Command::GetAll(RequestGetAll { keys }))
+    }
+
+    pub fn new_put_all(pairs: Vec<Kvpair>) -> Self {
+        Self::with_command(request::Command::PutAll(RequestPutAll { pairs }))
+    }
+
+    pub fn new_replicate(replication_id: &str, offset: u64) -> Self {
+        Self::with_command(request::Command::Replicate(RequestReplicate { replication_id: replication_id.to_owned(), offset }))
+    }
+
+    pub fn new_raft(message: RaftMessage) -> Self {
+        Self::with_command(request::Command::Raft(message))
+    }
+
+    pub fn new_add_member(id: u64, addr: &str) -> Self {
+        Self::with_command(request::Command::AddMember(RequestAddMember { id, addr: addr.to_owned() }))
+    }
+
+    pub fn new_remove_member(id: u64) -> Self {
+        Self::with_command(request::Command::RemoveMember(RequestRemoveMember { id }))
+    }
+
+    pub fn new_subscribe(topic: &str) -> Self {
+        Self::with_command(request::Command::Subscribe(RequestSubscribe { topic: topic.to_owned() }))
+    }
+
+    pub fn new_unsubscribe(topic: &str, id: u32) -> Self {
+        Self::with_command(request::Command::Unsubscribe(RequestUnsubscribe { topic: topic.to_owned(), id }))
+    }
+
+    pub fn new_publish(topic: &str, value: &[u8]) -> Self {
+        Self::with_command(request::Command::Publish(RequestPublish { topic: topic.to_owned(), value: value.to_vec() }))
+    }
+}
+
+impl TxnOp {
+    fn with_op(op: txn_op::Op) -> Self {
+        TxnOp { op: Some(op) }
+    }
+
+    pub fn get(key: &str) -> Self {
+        Self::with_op(txn_op::Op::Get(RequestGet { key: key.to_owned() }))
+    }
+
+    /// 条件: key存在或者不存在
+    pub fn exists(key: &str, exists: bool) -> Self {
+        Self::with_op(txn_op::Op::Check(TxnCheck { key: key.to_owned(), condition: Some(txn_check::Condition::Exists(exists)) }))
+    }
+
+    /// 条件: key存在并且值等于value
+    pub fn equals(key: &str, value: &[u8]) -> Self {
+        Self::with_op(txn_op::Op::Check(TxnCheck { key: key.to_owned(), condition: Some(txn_check::Condition::Equals(value.to_vec())) }))
+    }
+
+    pub fn put(key: &str, value: &[u8], ttl: u64) -> Self {
+        Self::with_op(txn_op::Op::Put(ResponsePut { key: key.to_owned(), value: value.to_vec(), ttl }))
+    }
+
+    pub fn delete(key: &str) -> Self {
+        Self::with_op(txn_op::Op::Delete(RequestDelete { key: key.to_owned() }))
+    }
+
+    pub fn incr(key: &str, delta: i64) -> Self {
+        Self::with_op(txn_op::Op::Incr(RequestIncr { key: key.to_owned(), delta }))
+    }
+
+    /// 操作涉及的key
+    pub fn key(&self) -> &str {
+        match &self.op {
+            Some(txn_op::Op::Get(RequestGet{key}))
+            | Some(txn_op::Op::Check(TxnCheck{key, ..}))
+            | Some(txn_op::Op::Put(ResponsePut{key, ..}))
+            | Some(txn_op::Op::Delete(RequestDelete{key}))
+            | Some(txn_op::Op::Incr(RequestIncr{key, ..})) => key,
+            None => "",
+        }
+    }
+
+    /// 是否会修改数据
+    pub fn is_write(&self) -> bool {
+        matches!(self.op, Some(txn_op::Op::Put(_)) | Some(txn_op::Op::Delete(_)) | Some(txn_op::Op::Incr(_)))
+    }
+}
+
+impl Kvpair {
+    pub fn new(key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn put(key: impl Into<String>, value: impl Into<Vec<u8>>, expire_at: u64) -> Self {
+        LogEntry {
+            op: Some(log_entry::Op::Put(LogPut { key: key.into(), value: value.into(), expire_at })),
+        }
+    }
+
+    pub fn delete(key: impl Into<String>) -> Self {
+        LogEntry {
+            op: Some(log_entry::Op::Delete(LogDelete { key: key.into() })),
+        }
+    }
+
+    /// expire_at为0时去掉过期时间
+    pub fn expire(key: impl Into<String>, expire_at: u64) -> Self {
+        LogEntry {
+            op: Some(log_entry::Op::Expire(LogExpire { key: key.into(), expire_at })),
+        }
+    }
+
+    pub fn batch(entries: Vec<LogEntry>) -> Self {
+        LogEntry {
+            op: Some(log_entry::Op::Batch(LogBatch { entries })),
         }
     }
 }
@@ -33,16 +260,86 @@ impl Response {
             code: 0,
             key,
             value,
+            ..Default::default()
         }
     }
 
-    pub fn not_found(key: String) -> Self {
+    pub fn with_ttl(key: String, ttl: i64) -> Self {
+        Self {
+            key,
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    pub fn subscribed(topic: String, id: u32) -> Self {
+        Self {
+            key: topic,
+            subscription_id: id,
+            ..Default::default()
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::default()
+    }
+
+    pub fn with_pairs(pairs: Vec<Kvpair>) -> Self {
+        Self {
+            pairs,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_replication(replication: Replication) -> Self {
+        Self {
+            replication: Some(replication),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_raft(raft: RaftMessage) -> Self {
+        Self {
+            raft: Some(raft),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_number(key: String, number: i64) -> Self {
         Self {
-            code: 404,
             key,
+            value: number.to_string().into_bytes(),
+            number,
             ..Default::default()
         }
     }
+
+    /// 条件不满足, 带上当前的值
+    pub fn conflict(key: String, current: Vec<u8>) -> Self {
+        Self {
+            key: key.clone(),
+            value: current,
+            ..KvError::Conflict(key).into()
+        }
+    }
+
+    /// 事务的结果, code和message是第一个失败的操作的
+    pub fn with_results(results: Vec<Response>) -> Self {
+        let failed = results.iter().find(|r| r.code != 0 && r.code != 404);
+        Self {
+            code: failed.map(|r| r.code).unwrap_or(0),
+            message: failed.map(|r| r.message.clone()).unwrap_or_default(),
+            results,
+            ..Default::default()
+        }
+    }
+
+    pub fn not_found(key: String) -> Self {
+        Self {
+            key: key.clone(),
+            ..KvError::NotFound(key).into()
+        }
+    }
 }
 
 impl From<Response> for Bytes {
@@ -0,0 +1,456 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::warn;
+use crate::client::{KvClient, RequestTimeout, Subscription};
+use crate::error::KvError;
+use crate::noise_codec::{self, Builder};
+use crate::protobuf::*;
+use crate::protobuf::request::Command;
+use crate::server::Writer;
+
+/// 每个后端在哈希环上的虚拟节点数
+pub const DEFAULT_VNODES: usize = 160;
+
+/// 一致性哈希环, 每个节点按虚拟节点分布在环上, key落在顺时针方向的第一个虚拟节点
+/// 增加或者删除一个节点时只有大约1/N的key需要移动
+#[derive(Debug)]
+pub struct HashRing {
+    ring: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String], vnodes: usize) -> Self {
+        let mut ring = BTreeMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for i in 0..vnodes.max(1) {
+                ring.insert(hash(format!("{}#{}", node, i).as_bytes()), index);
+            }
+        }
+        HashRing { ring }
+    }
+
+    /// key所在节点的下标, 环为空时返回None
+    pub fn node(&self, key: &str) -> Option<usize> {
+        let hash = hash(key.as_bytes());
+        self.ring.range(hash..).next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, index)| *index)
+    }
+}
+
+// fnv-1a之后再做一次murmur3的fmix64, 结果和rust版本无关, 不同的代理实例路由一致
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+// 一个后端kv服务, 连接断开之后下次请求时重新连接
+struct Backend {
+    addr: String,
+    noise: Builder,
+    client: Mutex<Option<KvClient>>,
+}
+
+impl Backend {
+    async fn client(&self) -> Result<KvClient> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref() {
+            return Ok(client.clone());
+        }
+        let new = KvClient::connect(&self.addr, self.noise.clone()).await?;
+        *client = Some(new.clone());
+        Ok(new)
+    }
+
+    async fn request(&self, request: Request) -> Response {
+        let client = match self.client().await {
+            Ok(client) => client,
+            Err(e) => return self.unavailable(e),
+        };
+        match client.request(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                // 单个请求超时不影响共用这个连接的其它请求, 只有连接出错时才丢掉, 已经重新连接过的不再丢掉
+                if !e.is::<RequestTimeout>() {
+                    let mut current = self.client.lock().await;
+                    if current.as_ref().map(|c| c.same_connection(&client)).unwrap_or(false) {
+                        current.take();
+                    }
+                }
+                self.unavailable(e)
+            }
+        }
+    }
+
+    fn unavailable(&self, e: anyhow::Error) -> Response {
+        KvError::Unavailable(format!("backend [{}]: {}", self.addr, e)).into()
+    }
+}
+
+/// 分片代理, 按key把请求转发到对应的后端, 多个key的请求拆分之后并发转发再合并结果
+pub struct Proxy {
+    ring: HashRing,
+    backends: Vec<Backend>,
+}
+
+impl Proxy {
+    /// noise用于连接后端, 需要是initiator
+    pub fn new(addrs: Vec<String>, vnodes: usize, noise: Builder) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(anyhow!("at least one backend is required"));
+        }
+        let ring = HashRing::new(&addrs, vnodes);
+        let backends = addrs.into_iter()
+            .map(|addr| Backend { addr, noise: noise.clone(), client: Mutex::new(None) })
+            .collect();
+        Ok(Proxy { ring, backends })
+    }
+
+    fn backend(&self, key: &str) -> (usize, &Backend) {
+        // new保证了至少有一个后端
+        let index = self.ring.node(key).unwrap_or_default();
+        (index, &self.backends[index])
+    }
+
+    /// 转发订阅之外的请求
+    pub async fn handle(&self, request: Request) -> Response {
+        let key = match &request.command {
+            Some(Command::Get(RequestGet{key}))
+            | Some(Command::Put(ResponsePut{key, ..}))
+            | Some(Command::Delete(RequestDelete{key}))
+            | Some(Command::Exists(RequestExists{key}))
+            | Some(Command::Expire(RequestExpire{key, ..}))
+            | Some(Command::Ttl(RequestTtl{key}))
+            | Some(Command::Persist(RequestPersist{key}))
+            | Some(Command::Cas(RequestCompareAndSwap{key, ..}))
+            | Some(Command::PutIfAbsent(RequestPutIfAbsent{key, ..}))
+            | Some(Command::Incr(RequestIncr{key, ..})) => key.clone(),
+            // 同一个topic的发布和订阅都在同一个后端
+            Some(Command::Publish(RequestPublish{topic, ..})) => topic.clone(),
+            Some(Command::Txn(RequestTxn{ops})) => match self.txn_backend(ops) {
+                Some(index) => return self.backends[index].request(request).await,
+                None => return KvError::InvalidCommand("keys of a transaction must be on the same backend".into()).into(),
+            },
+            Some(Command::Keys(RequestKeys{prefix})) => return self.keys(prefix).await,
+            Some(Command::GetAll(RequestGetAll{keys})) => return self.get_all(keys).await,
+            Some(Command::PutAll(RequestPutAll{pairs})) => return self.put_all(pairs.clone()).await,
+            None => return KvError::InvalidCommand("empty request".into()).into(),
+            _ => return KvError::InvalidCommand("command is not supported by the proxy".into()).into(),
+        };
+        self.backend(&key).1.request(request).await
+    }
+
+    // 事务不能跨后端, 所有的key都在同一个后端时返回它的下标
+    fn txn_backend(&self, ops: &[TxnOp]) -> Option<usize> {
+        let mut indexes = ops.iter().map(|op| self.backend(op.key()).0);
+        let first = indexes.next()?;
+        indexes.all(|index| index == first).then_some(first)
+    }
+
+    // 所有后端的结果合并之后排序
+    async fn keys(&self, prefix: &str) -> Response {
+        let responses = join_all(self.backends.iter().map(|b| b.request(Request::new_keys(prefix)))).await;
+        let mut pairs = Vec::new();
+        for response in responses {
+            if response.code != 0 {
+                return response;
+            }
+            pairs.extend(response.pairs);
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Response::with_pairs(pairs)
+    }
+
+    // 按后端拆分, 结果按请求中key的顺序返回
+    async fn get_all(&self, keys: &[String]) -> Response {
+        let mut groups: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+        for key in keys {
+            groups.entry(self.backend(key).0).or_default().push(key);
+        }
+        let requests = groups.iter().map(|(index, keys)| self.backends[*index].request(Request::new_get_all(keys)));
+        let mut values = HashMap::new();
+        for response in join_all(requests).await {
+            if response.code != 0 {
+                return response;
+            }
+            values.extend(response.pairs.into_iter().map(|p| (p.key, p.value)));
+        }
+        let pairs = keys.iter()
+            .filter_map(|key| values.get(key).map(|value| Kvpair::new(key.as_str(), value.clone())))
+            .collect();
+        Response::with_pairs(pairs)
+    }
+
+    // 不同后端之间不是原子的, 出错时已经写入的部分不会回滚
+    async fn put_all(&self, pairs: Vec<Kvpair>) -> Response {
+        let mut groups: BTreeMap<usize, Vec<Kvpair>> = BTreeMap::new();
+        for pair in pairs {
+            groups.entry(self.backend(&pair.key).0).or_default().push(pair);
+        }
+        let requests = groups.into_iter().map(|(index, pairs)| self.backends[index].request(Request::new_put_all(pairs)));
+        join_all(requests).await.into_iter()
+            .find(|response| response.code != 0)
+            .unwrap_or_else(Response::ok)
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<(usize, Subscription)> {
+        let (index, backend) = self.backend(topic);
+        let subscription = backend.client().await?.subscribe(topic).await?;
+        Ok((index, subscription))
+    }
+}
+
+// 代理上的一个订阅, 后台任务把后端推送的消息转发到客户端的连接
+struct ProxySubscription {
+    topic: String,
+    backend: usize,
+    backend_id: u32,
+    task: JoinHandle<()>,
+}
+
+/// 处理一个客户端连接, 协议和kv服务端完全一样
+pub async fn serve_proxy<T>(stream: T, proxy: Arc<Proxy>, noise: Builder) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let mut stream = noise.new_framed(stream)?;
+    noise_codec::handshake(&mut stream).await?;
+    let (tx, rx) = mpsc::channel(128);
+    let mut subscriptions: HashMap<u32, ProxySubscription> = HashMap::new();
+    let mut next_id: u32 = 0;
+    // 和kv服务端一样由单独的任务写入, 写被阻塞时仍然继续读取
+    let (sink, mut stream) = stream.split();
+    let (writer, mut written, mut write_task) = Writer::spawn(sink, rx);
+    let result = loop {
+        tokio::select! {
+            frame = stream.next(), if writer.available() > 0 => {
+                let buf = match frame {
+                    Some(Ok(buf)) => buf,
+                    _ => break Ok(()),
+                };
+                let permit = writer.reserve()?;
+                let request: Result<Request, _> = buf.try_into();
+                let request = match request {
+                    Ok(request) => request,
+                    Err(e) => {
+                        writer.queue(Response::from(KvError::from(e)), permit);
+                        continue;
+                    }
+                };
+                let id = request.id;
+                let response = match request.command {
+                    Some(Command::Subscribe(RequestSubscribe{topic})) => {
+                        match proxy.subscribe(&topic).await {
+                            Ok((backend, mut subscription)) => {
+                                next_id += 1;
+                                let (proxy_id, tx) = (next_id, tx.clone());
+                                let backend_id = subscription.id;
+                                let message_topic = topic.clone();
+                                let task = tokio::spawn(async move {
+                                    while let Some(value) = subscription.next().await {
+                                        let message = Response {
+                                            key: message_topic.clone(),
+                                            value,
+                                            subscription_id: proxy_id,
+                                            ..Default::default()
+                                        };
+                                        if tx.send(message).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                });
+                                subscriptions.insert(proxy_id, ProxySubscription { topic: topic.clone(), backend, backend_id, task });
+                                Response::subscribed(topic, proxy_id)
+                            }
+                            Err(e) => KvError::Unavailable(e.to_string()).into(),
+                        }
+                    }
+                    Some(Command::Unsubscribe(RequestUnsubscribe{topic, id: proxy_id})) => {
+                        match subscriptions.get(&proxy_id).filter(|s| s.topic == topic) {
+                            Some(_) => {
+                                let subscription = subscriptions.remove(&proxy_id).unwrap();
+                                unsubscribe(&proxy, subscription).await;
+                                Response::subscribed(topic, proxy_id)
+                            }
+                            None => Response::not_found(topic),
+                        }
+                    }
+                    // 其它请求并发转发
+                    _ => {
+                        let proxy = proxy.clone();
+                        let writer = writer.clone();
+                        tokio::spawn(async move {
+                            let mut response = proxy.handle(request).await;
+                            response.id = id;
+                            writer.queue(response, permit);
+                        });
+                        continue;
+                    }
+                };
+                writer.queue(Response { id, ..response }, permit);
+            }
+            // 写入之后释放了permit, 可以继续读取
+            Ok(()) = written.changed() => {}
+            result = &mut write_task => {
+                break result.map_err(|e| anyhow!(e)).and_then(|r| r);
+            }
+        }
+    };
+    write_task.abort();
+    // 连接断开后取消在后端的订阅
+    for (_, subscription) in subscriptions.drain() {
+        unsubscribe(&proxy, subscription).await;
+    }
+    result
+}
+
+async fn unsubscribe(proxy: &Proxy, subscription: ProxySubscription) {
+    subscription.task.abort();
+    let request = Request::new_unsubscribe(&subscription.topic, subscription.backend_id);
+    let response = proxy.backends[subscription.backend].request(request).await;
+    if response.code != 0 {
+        warn!("failed to unsubscribe [{}] on backend: {}", subscription.topic, response.message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use crate::noise_codec::NOISE_CODEC;
+    use crate::limit::ConnectionLimits;
+    use crate::server::serve_connection;
+    use crate::service::ServerState;
+    use super::*;
+
+    #[test]
+    fn test_hash_ring() {
+        let nodes: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:8888", i)).collect();
+        let ring = HashRing::new(&nodes, DEFAULT_VNODES);
+        let keys: Vec<String> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let mut counts = [0; 4];
+        for key in &keys {
+            counts[ring.node(key).unwrap()] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 1500), "{:?}", counts);
+
+        // 增加一个节点, 只有移动到新节点上的key改变了位置
+        let mut more = nodes.clone();
+        more.push("10.0.0.4:8888".to_owned());
+        let bigger = HashRing::new(&more, DEFAULT_VNODES);
+        let moved = keys.iter().filter(|k| ring.node(k) != bigger.node(k)).count();
+        assert!(keys.iter().all(|k| ring.node(k) == bigger.node(k) || bigger.node(k) == Some(4)));
+        assert!(moved < 3000, "{}", moved);
+        assert_eq!(HashRing::new(&[], DEFAULT_VNODES).node("a"), None);
+    }
+
+    async fn start_backend() -> (String, Arc<ServerState>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(ServerState::default());
+        let share = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_connection(stream, share.clone(), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+            }
+        });
+        (addr, state)
+    }
+
+    async fn start_proxy(backends: Vec<String>) -> std::net::SocketAddr {
+        let proxy = Arc::new(Proxy::new(backends, DEFAULT_VNODES, Builder::new(NOISE_CODEC, true)).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_proxy(stream, proxy.clone(), Builder::new(NOISE_CODEC, false)));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_proxy_routes_keys() {
+        let mut addrs = Vec::new();
+        let mut states = Vec::new();
+        for _ in 0..3 {
+            let (addr, state) = start_backend().await;
+            addrs.push(addr);
+            states.push(state);
+        }
+        let addr = start_proxy(addrs).await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        let pairs: Vec<Kvpair> = (0..30).map(|i| Kvpair::new(format!("key{:02}", i), format!("{}", i))).collect();
+        client.put_all(pairs).await.unwrap();
+        client.put("hello", b"world").await.unwrap();
+        assert_eq!(client.get("hello").await.unwrap(), Some(b"world".to_vec()));
+
+        // 每个key只在一个后端上, 并且每个后端都分到了key
+        let counts: Vec<usize> = states.iter().map(|s| s.handle(Request::new_keys("key")).pairs.len()).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 30);
+        assert!(counts.iter().all(|c| *c > 0), "{:?}", counts);
+
+        let keys = client.keys("key").await.unwrap();
+        assert_eq!(keys, (0..30).map(|i| format!("key{:02}", i)).collect::<Vec<_>>());
+        let pairs = client.get_all(&["key05", "missing", "key01"]).await.unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("key05", "5"), Kvpair::new("key01", "1")]);
+
+        // 事务只能在一个后端上执行
+        assert!(client.txn(vec![TxnOp::incr("key01", 1), TxnOp::get("key01")]).await.unwrap().is_some());
+        let ops = (0..30).map(|i| TxnOp::get(&format!("key{:02}", i))).collect();
+        assert!(client.txn(ops).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backend_keeps_connection_on_timeout() {
+        // 只接受一个连接, 不回复key为slow的请求
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Builder::new(NOISE_CODEC, false).new_framed(stream).unwrap();
+            noise_codec::handshake(&mut framed).await.unwrap();
+            while let Some(Ok(buf)) = framed.next().await {
+                let request: Request = buf.try_into().unwrap();
+                if request.command != Some(Command::Get(RequestGet { key: "slow".into() })) {
+                    framed.send(Response { id: request.id, ..Response::ok() }.into()).await.unwrap();
+                }
+            }
+        });
+        let client = KvClient::connect(&addr, Builder::new(NOISE_CODEC, true)).await.unwrap()
+            .timeout(Duration::from_millis(100));
+        let backend = Backend { addr, noise: Builder::new(NOISE_CODEC, true), client: Mutex::new(Some(client)) };
+        assert_eq!(backend.request(Request::new_get("slow")).await.code, 503);
+        assert_eq!(backend.request(Request::new_get("fast")).await.code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_subscribe() {
+        let (backend, _state) = start_backend().await;
+        let addr = start_proxy(vec![backend]).await;
+        let client = KvClient::connect(addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        let mut subscription = client.subscribe("news").await.unwrap();
+        client.publish("news", b"hello").await.unwrap();
+        let value = tokio::time::timeout(Duration::from_secs(1), subscription.next()).await.unwrap();
+        assert_eq!(value, Some(b"hello".to_vec()));
+        assert!(client.unsubscribe(&subscription).await.unwrap());
+        assert!(!client.unsubscribe(&subscription).await.unwrap());
+    }
+}
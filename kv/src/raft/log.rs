@@ -0,0 +1,302 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BytesMut};
+use prost::Message;
+use tracing::warn;
+use crate::protobuf::{LogEntry, Membership, RaftEntry, RaftHardState, RaftSnapshot};
+
+const LOG_FILE: &str = "raft.log";
+const LOG_TMP_FILE: &str = "raft.log.tmp";
+const STATE_FILE: &str = "raft.state";
+const STATE_TMP_FILE: &str = "raft.state.tmp";
+const SNAPSHOT_FILE: &str = "raft.snapshot";
+const SNAPSHOT_TMP_FILE: &str = "raft.snapshot.tmp";
+// 日志文件的开头, 后面是大端u64的起始位置, 文件中第一条记录的位置是它加1
+// 没有这个开头的旧文件从位置1开始
+const LOG_MAGIC: &[u8; 8] = b"KVRAFT\0\x01";
+const LOG_HEADER_LEN: usize = 16;
+
+/// raft的日志和投票状态, 指定目录时写入磁盘, 否则只保存在内存中
+/// 日志的位置从1开始, 0表示空日志之前的位置
+/// 压缩之后快照位置以及之前的记录都已经删除, 只保留快照
+#[derive(Debug, Default)]
+pub struct RaftLog {
+    dir: Option<PathBuf>,
+    file: Option<File>,
+    snapshot: RaftSnapshot,
+    // 从snapshot.last_index + 1开始的记录
+    entries: Vec<RaftEntry>,
+    pub term: u64,
+    // 0表示这个term还没有投票
+    pub voted_for: u64,
+}
+
+impl RaftLog {
+    /// 只保存在内存中的日志, 重启之后丢失, 用于测试
+    pub fn memory() -> Self {
+        Self::default()
+    }
+
+    /// 打开目录中的快照, 日志和投票状态
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(buf) => RaftHardState::decode(&buf[..])?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RaftHardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(buf) => RaftSnapshot::decode(&buf[..])?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RaftSnapshot::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(dir.join(LOG_FILE))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let (header, base) = match buf.len() >= LOG_HEADER_LEN && buf.starts_with(LOG_MAGIC) {
+            true => (LOG_HEADER_LEN, u64::from_be_bytes(buf[LOG_MAGIC.len()..LOG_HEADER_LEN].try_into()?)),
+            false => (0, 0),
+        };
+        let total = buf.len();
+        let mut buf = BytesMut::from(&buf[header..]);
+        let mut entries = Vec::new();
+        let mut valid = header;
+        while buf.has_remaining() {
+            match RaftEntry::decode_length_delimited(&mut buf) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    valid = total - buf.remaining();
+                }
+                Err(e) => {
+                    warn!("ignore broken tail of raft log: {:?}", e);
+                    break;
+                }
+            }
+        }
+        file.set_len(valid as u64)?;
+        if base > snapshot.last_index {
+            return Err(anyhow!("raft log starts at {} after the snapshot at {}", base + 1, snapshot.last_index));
+        }
+        // 写入快照之后还没来得及重写日志, 快照之前的记录丢掉, 和快照冲突时全部丢掉
+        let skip = (snapshot.last_index - base) as usize;
+        let matched = skip == 0 || entries.get(skip - 1).map(|e| e.term) == Some(snapshot.last_term);
+        match matched {
+            true => { entries.drain(..skip.min(entries.len())); }
+            false => entries.clear(),
+        }
+        let mut log = RaftLog {
+            dir: Some(dir),
+            file: Some(file),
+            snapshot,
+            entries,
+            term: state.term,
+            voted_for: state.voted_for,
+        };
+        if header == 0 || base != log.snapshot.last_index {
+            log.rewrite()?;
+        }
+        Ok(log)
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map(|e| e.term).unwrap_or(self.snapshot.last_term)
+    }
+
+    /// 最近的快照, 没有压缩过时是位置0的空快照
+    pub fn snapshot(&self) -> &RaftSnapshot {
+        &self.snapshot
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot.last_index
+    }
+
+    /// 某个位置的term, 0返回0, 超出日志或者已经压缩时返回None
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match index == self.snapshot.last_index {
+            true => Some(self.snapshot.last_term),
+            false => self.get(index).map(|e| e.term),
+        }
+    }
+
+    /// 某个位置的记录, 已经压缩到快照中时返回None
+    pub fn get(&self, index: u64) -> Option<&RaftEntry> {
+        index.checked_sub(self.snapshot.last_index + 1).and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// 从index开始最多max条记录, 总大小超过max_bytes之后不再继续, 但至少有一条
+    pub fn entries_from(&self, index: u64, max: usize, max_bytes: usize) -> Vec<RaftEntry> {
+        let start = index.saturating_sub(self.snapshot.last_index + 1) as usize;
+        let mut size = 0;
+        self.entries.iter().skip(start).take(max)
+            .take_while(|entry| {
+                let first = size == 0;
+                size += entry.encoded_len();
+                first || size <= max_bytes
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// 从后往前查找, 返回最后一条满足条件的记录的位置, 不包括快照
+    pub fn rfind(&self, f: impl Fn(&RaftEntry) -> bool) -> Option<u64> {
+        self.entries.iter().rposition(f).map(|i| self.snapshot.last_index + i as u64 + 1)
+    }
+
+    pub fn append(&mut self, entries: Vec<RaftEntry>) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let mut buf = Vec::new();
+            for entry in &entries {
+                entry.encode_length_delimited(&mut buf)?;
+            }
+            file.write_all(&buf)?;
+            file.sync_data()?;
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// 删除index以及之后的记录, 只有和leader冲突时才会发生, 直接重写整个文件
+    pub fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate(index.saturating_sub(self.snapshot.last_index + 1) as usize);
+        self.rewrite()
+    }
+
+    /// 把index以及之前的记录压缩成快照, entries是执行到index之后的全部数据, membership是那时的成员
+    pub fn compact(&mut self, index: u64, membership: Option<Membership>, entries: Vec<LogEntry>) -> Result<()> {
+        let last_term = self.term_at(index)
+            .filter(|_| index > self.snapshot.last_index)
+            .ok_or_else(|| anyhow!("cannot compact raft log at {}", index))?;
+        let snapshot = RaftSnapshot { last_index: index, last_term, membership, entries };
+        self.save_snapshot(&snapshot)?;
+        self.entries.drain(..(index - self.snapshot.last_index) as usize);
+        self.snapshot = snapshot;
+        self.rewrite()
+    }
+
+    /// 安装leader发送过来的快照, 快照之后和快照一致的记录保留, 否则清空日志
+    pub fn install(&mut self, snapshot: RaftSnapshot) -> Result<()> {
+        let matched = snapshot.last_index > self.snapshot.last_index
+            && self.term_at(snapshot.last_index) == Some(snapshot.last_term);
+        self.save_snapshot(&snapshot)?;
+        match matched {
+            true => { self.entries.drain(..(snapshot.last_index - self.snapshot.last_index) as usize); }
+            false => self.entries.clear(),
+        }
+        self.snapshot = snapshot;
+        self.rewrite()
+    }
+
+    // 先写快照再重写日志, 中间中断时打开的时候会丢掉已经在快照中的记录
+    fn save_snapshot(&self, snapshot: &RaftSnapshot) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let tmp = dir.join(SNAPSHOT_TMP_FILE);
+            let mut file = File::create(&tmp)?;
+            file.write_all(&snapshot.encode_to_vec())?;
+            file.sync_all()?;
+            fs::rename(&tmp, dir.join(SNAPSHOT_FILE))?;
+        }
+        Ok(())
+    }
+
+    // 按内存中的记录重写日志文件
+    fn rewrite(&mut self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let tmp = dir.join(LOG_TMP_FILE);
+            let mut buf = LOG_MAGIC.to_vec();
+            buf.extend_from_slice(&self.snapshot.last_index.to_be_bytes());
+            for entry in &self.entries {
+                entry.encode_length_delimited(&mut buf)?;
+            }
+            let mut file = File::create(&tmp)?;
+            file.write_all(&buf)?;
+            file.sync_all()?;
+            fs::rename(&tmp, dir.join(LOG_FILE))?;
+            self.file = Some(OpenOptions::new().append(true).open(dir.join(LOG_FILE))?);
+        }
+        Ok(())
+    }
+
+    /// 修改term和投票, 必须在回复之前写入磁盘
+    pub fn set_hard_state(&mut self, term: u64, voted_for: u64) -> Result<()> {
+        if (term, voted_for) == (self.term, self.voted_for) {
+            return Ok(());
+        }
+        if let Some(dir) = &self.dir {
+            let tmp = dir.join(STATE_TMP_FILE);
+            let mut file = File::create(&tmp)?;
+            file.write_all(&RaftHardState { term, voted_for }.encode_to_vec())?;
+            file.sync_all()?;
+            fs::rename(&tmp, dir.join(STATE_FILE))?;
+        }
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u64) -> RaftEntry {
+        RaftEntry { term, data: None, timestamp: 0 }
+    }
+
+    #[test]
+    fn test_raft_log_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        log.append(vec![entry(1), entry(1), entry(2)]).unwrap();
+        log.set_hard_state(2, 3).unwrap();
+        log.truncate(3).unwrap();
+        log.append(vec![entry(3)]).unwrap();
+        drop(log);
+
+        let log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!((log.term, log.voted_for), (2, 3));
+        assert_eq!(log.last_index(), 3);
+        assert_eq!(log.term_at(2), Some(1));
+        assert_eq!(log.term_at(3), Some(3));
+        assert_eq!(log.term_at(4), None);
+        assert_eq!(log.entries_from(2, 10, usize::MAX).len(), 2);
+        assert_eq!(log.entries_from(2, 10, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_raft_log_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        log.append(vec![entry(1), entry(1), entry(2), entry(2)]).unwrap();
+        log.compact(3, None, vec![LogEntry::put("a", "1", 0)]).unwrap();
+        log.append(vec![entry(3)]).unwrap();
+        drop(log);
+
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!(log.snapshot_index(), 3);
+        assert_eq!(log.snapshot().entries.len(), 1);
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.term_at(2), None);
+        assert_eq!(log.term_at(3), Some(2));
+        assert_eq!(log.get(5).map(|e| e.term), Some(3));
+        assert_eq!(log.rfind(|_| true), Some(5));
+        assert_eq!(log.entries_from(1, 10, usize::MAX).len(), 2);
+
+        // 和快照一致的记录保留, 冲突时清空
+        let snapshot = |last_index, last_term| RaftSnapshot { last_index, last_term, ..Default::default() };
+        log.install(snapshot(4, 2)).unwrap();
+        assert_eq!((log.snapshot_index(), log.last_index()), (4, 5));
+        log.install(snapshot(6, 4)).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (6, 4));
+        drop(log);
+        let log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (6, 4));
+    }
+}
@@ -0,0 +1,1087 @@
+mod log;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use futures::stream::{FuturesUnordered, StreamExt};
+use prost::Message;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+use crate::acl::Identity;
+use crate::client::KvClient;
+use crate::error::KvError;
+use crate::noise_codec::Builder;
+use crate::protobuf::*;
+use crate::protobuf::raft_entry::Data;
+use crate::protobuf::raft_message::Body;
+use crate::protobuf::request::Command;
+use crate::service::ServerState;
+use crate::ttl::now_ms;
+pub use log::RaftLog;
+
+const TICK: Duration = Duration::from_millis(20);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// 选举超时在[MIN, MIN + JITTER)之间随机
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(500);
+const ELECTION_TIMEOUT_JITTER: u64 = 500;
+const RPC_TIMEOUT: Duration = Duration::from_millis(300);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// 一次AppendEntries最多发送的记录数和字节数
+const MAX_APPEND_ENTRIES: usize = 100;
+const MAX_APPEND_BYTES: usize = 4 * 1024 * 1024;
+// 执行了这么多条记录之后压缩日志
+pub const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 10000;
+// 一条InstallSnapshot消息最多包含的数据条数, 字节数的限制和AppendEntries一样
+const MAX_SNAPSHOT_ENTRIES: usize = 1000;
+// leader检查是否有过期key的间隔, 有的话写入一条空记录, 每个节点执行到这条记录时删除
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// leader记录的每个follower的复制进度
+#[derive(Debug)]
+struct Progress {
+    next_index: u64,
+    match_index: u64,
+    // 同一个follower同时只有一个AppendEntries在发送
+    inflight: bool,
+    // 需要的记录已经压缩时发送快照, 这是follower已经收到的快照数据条数
+    snapshot_offset: u64,
+}
+
+// 需要发送的消息: 节点id, 地址, 消息
+type Outgoing = Vec<(u64, String, RaftMessage)>;
+
+/// 基于raft的集群节点
+/// 写请求作为日志复制到多数节点之后才在每个节点上执行, 读请求由leader确认自己仍然是leader之后执行,
+/// 所以只有leader处理客户端的读写, 其它节点返回421和leader的地址
+/// 成员变更每次只能增加或者删除一个节点, 写入日志之后立即生效
+pub struct RaftNode {
+    id: u64,
+    core: Mutex<Core>,
+    // ServerState持有RaftNode, 这里只保留弱引用
+    state: Weak<ServerState>,
+    noise: Builder,
+    // 其它节点的公钥, 没有开启认证时只接受用这个公钥连接的raft消息
+    peer_key: Option<Vec<u8>>,
+    // 到其它节点的连接, 按地址复用
+    peers: DashMap<String, KvClient>,
+    // 已经执行到的日志位置
+    applied: watch::Sender<u64>,
+}
+
+struct Core {
+    id: u64,
+    role: Role,
+    log: RaftLog,
+    commit_index: u64,
+    last_applied: u64,
+    leader: Option<u64>,
+    // 日志中没有成员变更时使用启动时配置的成员
+    bootstrap: BTreeMap<u64, String>,
+    members: BTreeMap<u64, String>,
+    progress: HashMap<u64, Progress>,
+    votes: HashSet<u64>,
+    election_deadline: Instant,
+    heartbeat_at: Instant,
+    reap_at: Instant,
+    last_heard: Instant,
+    // leader当选时写入的空记录的位置, 这条记录生效之前之前term的记录是否已经提交是不确定的
+    leader_start: u64,
+    // 等待日志执行的请求: 位置 -> (term, 执行结果)
+    waiters: HashMap<u64, (u64, oneshot::Sender<Response>)>,
+    // follower正在接收的快照
+    receiving: Option<RaftSnapshot>,
+    snapshot_threshold: u64,
+    stopped: bool,
+}
+
+impl RaftNode {
+    /// 创建节点并开始选举和复制, members是集群初次启动时的成员
+    /// 新加入的节点可以不在members中, 由leader通过成员变更加入之后从日志中得到集群的成员
+    pub fn start(id: u64, members: BTreeMap<u64, String>, log: RaftLog, state: &Arc<ServerState>, noise: Builder) -> Result<Arc<Self>> {
+        let now = Instant::now();
+        // 快照中的记录都已经提交, 启动之后先从快照恢复数据
+        let commit_index = log.snapshot_index();
+        let mut core = Core {
+            id,
+            role: Role::Follower,
+            log,
+            commit_index,
+            last_applied: 0,
+            leader: None,
+            bootstrap: members,
+            members: BTreeMap::new(),
+            progress: HashMap::new(),
+            votes: HashSet::new(),
+            election_deadline: now + election_timeout(),
+            heartbeat_at: now,
+            reap_at: now,
+            last_heard: now,
+            leader_start: 0,
+            waiters: HashMap::new(),
+            receiving: None,
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            stopped: false,
+        };
+        core.refresh_members();
+        let node = Arc::new(RaftNode {
+            id,
+            core: Mutex::new(core),
+            state: Arc::downgrade(state),
+            peer_key: noise.pinned_public_key().map(|key| key.to_vec()),
+            noise,
+            peers: DashMap::new(),
+            applied: watch::channel(0).0,
+        });
+        state.set_cluster(node.clone())?;
+        tokio::spawn(node.clone().run());
+        Ok(node)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.lock().role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+
+    /// 当前leader的地址
+    pub fn leader(&self) -> Option<String> {
+        let core = self.lock();
+        core.leader.and_then(|id| core.members.get(&id).cloned())
+    }
+
+    /// 当前的集群成员
+    pub fn members(&self) -> BTreeMap<u64, String> {
+        self.lock().members.clone()
+    }
+
+    /// 连接的身份是否是集群的其它节点, 也就是握手时用的公钥和连接其它节点时固定的公钥一致
+    pub fn is_peer(&self, identity: Option<&Identity>) -> bool {
+        match (&self.peer_key, identity) {
+            (Some(key), Some(Identity::PublicKey(identity))) => key == identity,
+            _ => false,
+        }
+    }
+
+    /// 执行了多少条记录之后压缩日志, 压缩时把当前的数据保存为快照
+    pub fn set_snapshot_threshold(&self, threshold: u64) {
+        self.lock().snapshot_threshold = threshold.max(1);
+    }
+
+    /// 停止节点, 之后不再参与选举和复制, 也不再处理客户端的请求
+    pub fn stop(&self) {
+        let mut core = self.lock();
+        core.stopped = true;
+        core.role = Role::Follower;
+        core.leader = None;
+        core.waiters.clear();
+    }
+
+    /// 处理集群模式下的请求
+    pub async fn handle(self: &Arc<Self>, mut request: Request) -> Response {
+        let result = match &request.command {
+            Some(Command::Raft(_)) => match request.command.take() {
+                Some(Command::Raft(message)) => self.step(message),
+                _ => unreachable!(),
+            },
+            Some(Command::AddMember(_)) | Some(Command::RemoveMember(_)) => self.change_membership(request).await,
+            Some(Command::Txn(RequestTxn{ops})) if !ops.iter().any(|op| op.is_write()) => self.read(request).await,
+            Some(Command::Put(_)) | Some(Command::Delete(_)) | Some(Command::PutAll(_))
+            | Some(Command::Expire(_)) | Some(Command::Persist(_)) | Some(Command::Cas(_))
+            | Some(Command::PutIfAbsent(_)) | Some(Command::Incr(_)) | Some(Command::Txn(_)) => {
+                // 请求id只对当前连接有意义, 不写入日志
+                request.id = 0;
+                self.propose(Data::Request(request)).await
+            }
+            Some(Command::Get(_)) | Some(Command::Exists(_)) | Some(Command::Keys(_))
+            | Some(Command::GetAll(_)) | Some(Command::Ttl(_)) => self.read(request).await,
+            // 发布订阅等不涉及数据的请求只在当前节点处理
+            _ => self.server_state().map(|state| state.handle(request)),
+        };
+        result.unwrap_or_else(Response::from)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Core> {
+        self.core.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn server_state(&self) -> Result<Arc<ServerState>, KvError> {
+        self.state.upgrade().ok_or_else(|| KvError::Internal("server is shutting down".into()))
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            if self.state.strong_count() == 0 {
+                break;
+            }
+            let outgoing = {
+                let mut core = self.lock();
+                if core.stopped {
+                    break;
+                }
+                let now = Instant::now();
+                let mut outgoing = core.tick(now);
+                if core.role == Role::Leader && now >= core.reap_at {
+                    core.reap_at = now + REAP_INTERVAL;
+                    let expired = self.state.upgrade().map(|state| state.has_expired(now_ms())).unwrap_or(false);
+                    if let (true, Ok(messages)) = (expired, &mut outgoing) {
+                        match core.propose(None) {
+                            Ok((_, more)) => messages.extend(more),
+                            Err(e) => error!("raft node {} failed to propose reap: {:?}", self.id, e),
+                        }
+                    }
+                }
+                self.apply_committed(&mut core);
+                outgoing
+            };
+            match outgoing {
+                Ok(outgoing) => self.send_all(outgoing),
+                Err(e) => error!("raft node {} failed to tick: {:?}", self.id, e),
+            }
+        }
+        info!("raft node {} stopped", self.id);
+    }
+
+    // 收到其它节点的请求
+    fn step(&self, message: RaftMessage) -> Result<Response, KvError> {
+        let mut core = self.lock();
+        if core.stopped {
+            return Err(KvError::Internal("node stopped".into()));
+        }
+        let reply = core.step(message)?;
+        self.apply_committed(&mut core);
+        Ok(Response::with_raft(reply))
+    }
+
+    // 收到其它节点的回复
+    fn handle_reply(self: &Arc<Self>, peer: u64, reply: Result<RaftMessage>) {
+        let outgoing = {
+            let mut core = self.lock();
+            let outgoing = match reply {
+                Ok(reply) => core.on_reply(peer, reply),
+                Err(e) => {
+                    debug!("raft node {} failed to send to {}: {:?}", self.id, peer, e);
+                    core.on_failure(peer);
+                    Ok(vec![])
+                }
+            };
+            self.apply_committed(&mut core);
+            outgoing
+        };
+        match outgoing {
+            Ok(outgoing) => self.send_all(outgoing),
+            Err(e) => error!("raft node {} failed to handle reply: {:?}", self.id, e),
+        }
+    }
+
+    fn send_all(self: &Arc<Self>, outgoing: Outgoing) {
+        for (peer, addr, message) in outgoing {
+            let node = self.clone();
+            tokio::spawn(async move {
+                let reply = node.call(&addr, message).await;
+                node.handle_reply(peer, reply);
+            });
+        }
+    }
+
+    async fn call(&self, addr: &str, message: RaftMessage) -> Result<RaftMessage> {
+        let client = match self.peers.get(addr) {
+            Some(client) => client.clone(),
+            None => {
+                let client = KvClient::connect(addr, self.noise.clone()).await?.timeout(RPC_TIMEOUT);
+                self.peers.insert(addr.to_owned(), client.clone());
+                client
+            }
+        };
+        let response = match client.request(Request::new_raft(message)).await {
+            Ok(response) => response,
+            Err(e) => {
+                // 连接可能已经断开, 下次重新连接
+                self.peers.remove(addr);
+                return Err(e);
+            }
+        };
+        if response.code != 0 {
+            return Err(anyhow!("peer returned code {}: {}", response.code, response.message));
+        }
+        response.raft.ok_or_else(|| anyhow!("missing raft message in response"))
+    }
+
+    // 执行已经提交的日志, leader上把结果交给等待的请求
+    fn apply_committed(&self, core: &mut Core) {
+        let state = match self.state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        // 启动或者收到leader的快照之后, 需要执行的记录已经压缩, 直接用快照替换数据
+        if core.last_applied < core.log.snapshot_index() {
+            let snapshot = core.log.snapshot();
+            if let Err(e) = state.restore(&snapshot.entries) {
+                error!("raft node {} failed to restore snapshot: {:?}", core.id, e);
+                return;
+            }
+            info!("raft node {} restored snapshot at {}", core.id, snapshot.last_index);
+            core.last_applied = snapshot.last_index;
+        }
+        while core.last_applied < core.commit_index {
+            let index = core.last_applied + 1;
+            let entry = match core.log.get(index) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            core.last_applied = index;
+            let response = match entry.data {
+                Some(Data::Request(request)) => state.handle_at(request, entry.timestamp),
+                Some(Data::Membership(membership)) => {
+                    // 把自己删除的leader在变更提交之后退位
+                    if core.role == Role::Leader && !membership.members.iter().any(|m| m.id == core.id) {
+                        info!("raft node {} removed from the cluster, step down", core.id);
+                        core.role = Role::Follower;
+                        core.leader = None;
+                        core.progress.clear();
+                    }
+                    Response::ok()
+                }
+                None => {
+                    if let Err(e) = state.reap_expired_at(entry.timestamp) {
+                        error!("raft node {} failed to reap expired keys: {:?}", core.id, e);
+                    }
+                    Response::ok()
+                }
+            };
+            if let Some((term, tx)) = core.waiters.remove(&index) {
+                if term == entry.term {
+                    let _ = tx.send(response);
+                }
+            }
+        }
+        if core.last_applied - core.log.snapshot_index() >= core.snapshot_threshold {
+            if let Err(e) = compact(core, &state) {
+                error!("raft node {} failed to compact log: {:?}", core.id, e);
+            }
+        }
+        self.applied.send_replace(core.last_applied);
+    }
+
+    async fn propose(self: &Arc<Self>, data: Data) -> Result<Response, KvError> {
+        let (rx, outgoing) = {
+            let mut core = self.lock();
+            self.propose_locked(&mut core, data)?
+        };
+        self.send_all(outgoing);
+        wait_commit(rx).await
+    }
+
+    fn propose_locked(&self, core: &mut Core, data: Data) -> Result<(oneshot::Receiver<Response>, Outgoing), KvError> {
+        let (index, outgoing) = core.propose(Some(data))?;
+        let (tx, rx) = oneshot::channel();
+        core.waiters.insert(index, (core.log.term, tx));
+        // 单节点的集群直接提交
+        self.apply_committed(core);
+        Ok((rx, outgoing))
+    }
+
+    async fn change_membership(self: &Arc<Self>, request: Request) -> Result<Response, KvError> {
+        let (rx, outgoing) = {
+            let mut core = self.lock();
+            core.check_leader()?;
+            // 同时只能有一个未提交的成员变更, 并且要等当选时的空记录提交之后
+            let pending = core.log.rfind(is_membership).map(|i| i > core.commit_index).unwrap_or(false);
+            if pending || core.commit_index < core.leader_start {
+                return Err(KvError::InvalidCommand("another membership change is in progress".into()));
+            }
+            let mut members = core.members.clone();
+            match request.command {
+                Some(Command::AddMember(RequestAddMember { id, addr })) => {
+                    if id == 0 || addr.is_empty() {
+                        return Err(KvError::InvalidCommand("member id and addr are required".into()));
+                    }
+                    members.insert(id, addr);
+                }
+                Some(Command::RemoveMember(RequestRemoveMember { id })) => {
+                    if members.remove(&id).is_none() {
+                        return Err(KvError::NotFound(format!("member {}", id)));
+                    }
+                    if members.is_empty() {
+                        return Err(KvError::InvalidCommand("cannot remove the last member".into()));
+                    }
+                }
+                _ => unreachable!(),
+            }
+            info!("raft node {} proposes members {:?}", self.id, members);
+            let members = members.into_iter().map(|(id, addr)| Member { id, addr }).collect();
+            self.propose_locked(&mut core, Data::Membership(Membership { members }))?
+        };
+        self.send_all(outgoing);
+        wait_commit(rx).await
+    }
+
+    // 确认自己仍然是leader, 等到确认时已经提交的日志执行之后再读取
+    async fn read(self: &Arc<Self>, request: Request) -> Result<Response, KvError> {
+        let (term, read_index, quorum, voter, messages) = {
+            let core = self.lock();
+            core.check_leader()?;
+            let messages: Outgoing = core.progress.keys().filter_map(|id| core.heartbeat_for(*id)).collect();
+            let voter = core.members.contains_key(&core.id) as usize;
+            (core.log.term, core.commit_index.max(core.leader_start), core.quorum(), voter, messages)
+        };
+        if voter < quorum {
+            let mut calls: FuturesUnordered<_> = messages.into_iter()
+                .map(|(peer, addr, message)| async move { (peer, self.call(&addr, message).await) })
+                .collect();
+            let confirm = async {
+                let mut acks = voter;
+                while let Some((peer, reply)) = calls.next().await {
+                    let ack = matches!(&reply, Ok(reply) if reply.term == term);
+                    self.handle_reply(peer, reply);
+                    if ack {
+                        acks += 1;
+                        if acks >= quorum {
+                            return true;
+                        }
+                    }
+                }
+                false
+            };
+            if !tokio::time::timeout(RPC_TIMEOUT * 2, confirm).await.unwrap_or(false) {
+                return Err(KvError::Internal("cannot confirm leadership".into()));
+            }
+        }
+        let mut applied = self.applied.subscribe();
+        let wait = async {
+            while *applied.borrow_and_update() < read_index {
+                if applied.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(PROPOSE_TIMEOUT, wait).await
+            .map_err(|_| KvError::Internal("timeout waiting for the log to be applied".into()))?;
+        Ok(self.server_state()?.handle(request))
+    }
+}
+
+async fn wait_commit(rx: oneshot::Receiver<Response>) -> Result<Response, KvError> {
+    match tokio::time::timeout(PROPOSE_TIMEOUT, rx).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(_)) => Err(KvError::Internal("leadership changed before the request was committed".into())),
+        Err(_) => Err(KvError::Internal("timeout waiting for the request to be committed".into())),
+    }
+}
+
+fn is_membership(entry: &RaftEntry) -> bool {
+    matches!(entry.data, Some(Data::Membership(_)))
+}
+
+// 把已经执行的记录压缩成快照, 快照中的成员是执行到那里时的成员
+fn compact(core: &mut Core, state: &ServerState) -> Result<()> {
+    let index = core.last_applied;
+    let membership = (core.log.snapshot_index() + 1..=index).rev()
+        .find_map(|i| match core.log.get(i) {
+            Some(RaftEntry { data: Some(Data::Membership(membership)), .. }) => Some(membership.clone()),
+            _ => None,
+        })
+        .or_else(|| core.log.snapshot().membership.clone());
+    let entries = state.snapshot_entries()?;
+    info!("raft node {} compacts log at {} with {} keys", core.id, index, entries.len());
+    core.log.compact(index, membership, entries)
+}
+
+// 每个节点的选举超时随机错开, 避免同时发起选举
+fn election_timeout() -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    ELECTION_TIMEOUT_MIN + Duration::from_millis(random % ELECTION_TIMEOUT_JITTER)
+}
+
+impl Core {
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn message(&self, body: Body) -> RaftMessage {
+        RaftMessage {
+            term: self.log.term,
+            from: self.id,
+            body: Some(body),
+        }
+    }
+
+    fn check_leader(&self) -> Result<(), KvError> {
+        if self.stopped {
+            return Err(KvError::Internal("node stopped".into()));
+        }
+        if self.role != Role::Leader {
+            let leader = self.leader.and_then(|id| self.members.get(&id).cloned());
+            return Err(KvError::NotLeader(leader.unwrap_or_else(|| "unknown".to_owned())));
+        }
+        Ok(())
+    }
+
+    // 成员以日志中最后一次变更为准, 不管是否已经提交, 日志中没有时看快照中的成员
+    fn refresh_members(&mut self) {
+        let membership = match self.log.rfind(is_membership).and_then(|index| self.log.get(index)) {
+            Some(RaftEntry { data: Some(Data::Membership(membership)), .. }) => Some(membership),
+            _ => self.log.snapshot().membership.as_ref(),
+        };
+        self.members = match membership {
+            Some(membership) => membership.members.iter().map(|m| (m.id, m.addr.clone())).collect(),
+            None => self.bootstrap.clone(),
+        };
+        if self.role == Role::Leader {
+            let next_index = self.log.last_index() + 1;
+            let (id, members) = (self.id, &self.members);
+            self.progress.retain(|peer, _| members.contains_key(peer));
+            for peer in members.keys().filter(|peer| **peer != id) {
+                self.progress.entry(*peer).or_insert(Progress { next_index, match_index: 0, inflight: false, snapshot_offset: 0 });
+            }
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) -> Result<()> {
+        if term > self.log.term {
+            self.log.set_hard_state(term, 0)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+        Ok(())
+    }
+
+    fn tick(&mut self, now: Instant) -> Result<Outgoing> {
+        match self.role {
+            Role::Leader if now >= self.heartbeat_at => {
+                self.heartbeat_at = now + HEARTBEAT_INTERVAL;
+                Ok(self.broadcast_append(true))
+            }
+            // 不在集群中的节点不发起选举
+            Role::Follower | Role::Candidate if now >= self.election_deadline && self.members.contains_key(&self.id) => {
+                self.start_election()
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn start_election(&mut self) -> Result<Outgoing> {
+        let term = self.log.term + 1;
+        self.log.set_hard_state(term, self.id)?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.election_deadline = Instant::now() + election_timeout();
+        info!("raft node {} starts election for term {}", self.id, term);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let message = self.message(Body::RequestVote(RequestVote {
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        }));
+        Ok(self.members.iter()
+            .filter(|(id, _)| **id != self.id)
+            .map(|(id, addr)| (*id, addr.clone(), message.clone()))
+            .collect())
+    }
+
+    fn become_leader(&mut self) -> Result<Outgoing> {
+        info!("raft node {} becomes leader of term {}", self.id, self.log.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.votes.clear();
+        // 写入一条空记录, 提交之后之前term的记录也随之提交
+        self.append_new(None)?;
+        self.leader_start = self.log.last_index();
+        self.progress.clear();
+        self.refresh_members();
+        self.heartbeat_at = Instant::now() + HEARTBEAT_INTERVAL;
+        self.advance_commit();
+        Ok(self.broadcast_append(false))
+    }
+
+    // 带ttl的请求在执行时才计算过期时间, 记录中保存leader写入的时间, 每个节点用它计算出同样的过期时间
+    fn append_new(&mut self, data: Option<Data>) -> Result<()> {
+        self.log.append(vec![RaftEntry { term: self.log.term, data, timestamp: now_ms() }])
+    }
+
+    fn propose(&mut self, data: Option<Data>) -> Result<(u64, Outgoing), KvError> {
+        self.check_leader()?;
+        let membership = matches!(data, Some(Data::Membership(_)));
+        self.append_new(data)?;
+        if membership {
+            self.refresh_members();
+        }
+        self.advance_commit();
+        Ok((self.log.last_index(), self.broadcast_append(false)))
+    }
+
+    // heartbeat为true时即使没有新的记录也发送
+    fn broadcast_append(&mut self, heartbeat: bool) -> Outgoing {
+        let peers: Vec<u64> = self.progress.keys().copied().collect();
+        peers.into_iter().filter_map(|peer| self.append_for(peer, heartbeat)).collect()
+    }
+
+    fn append_for(&mut self, peer: u64, heartbeat: bool) -> Option<(u64, String, RaftMessage)> {
+        let last_index = self.log.last_index();
+        let progress = self.progress.get_mut(&peer)?;
+        if progress.inflight || (!heartbeat && progress.next_index > last_index) {
+            return None;
+        }
+        progress.inflight = true;
+        if progress.next_index <= self.log.snapshot_index() {
+            let offset = progress.snapshot_offset;
+            return self.snapshot_message(peer, offset);
+        }
+        let prev = progress.next_index - 1;
+        self.append_message(peer, prev, self.log.entries_from(prev + 1, MAX_APPEND_ENTRIES, MAX_APPEND_BYTES))
+    }
+
+    // follower需要的记录已经压缩, 从offset开始发送一部分快照
+    fn snapshot_message(&self, peer: u64, offset: u64) -> Option<(u64, String, RaftMessage)> {
+        let addr = self.members.get(&peer)?.clone();
+        let snapshot = self.log.snapshot();
+        let mut size = 0;
+        let entries: Vec<LogEntry> = snapshot.entries.iter().skip(offset as usize).take(MAX_SNAPSHOT_ENTRIES)
+            .take_while(|entry| {
+                let first = size == 0;
+                size += entry.encoded_len();
+                first || size <= MAX_APPEND_BYTES
+            })
+            .cloned()
+            .collect();
+        let done = offset as usize + entries.len() >= snapshot.entries.len();
+        let message = self.message(Body::InstallSnapshot(InstallSnapshot {
+            last_index: snapshot.last_index,
+            last_term: snapshot.last_term,
+            membership: snapshot.membership.clone(),
+            offset,
+            entries,
+            done,
+        }));
+        Some((peer, addr, message))
+    }
+
+    // 不带记录的AppendEntries, 用于确认leader身份, 不受inflight的限制
+    fn heartbeat_for(&self, peer: u64) -> Option<(u64, String, RaftMessage)> {
+        let prev = self.progress.get(&peer)?.next_index - 1;
+        self.append_message(peer, prev, vec![])
+    }
+
+    fn append_message(&self, peer: u64, prev: u64, entries: Vec<RaftEntry>) -> Option<(u64, String, RaftMessage)> {
+        let addr = self.members.get(&peer)?.clone();
+        let message = self.message(Body::AppendEntries(AppendEntries {
+            prev_log_index: prev,
+            prev_log_term: self.log.term_at(prev).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+        }));
+        Some((peer, addr, message))
+    }
+
+    // 多数节点已经复制的当前term的记录可以提交
+    fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        for index in (self.commit_index + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != Some(self.log.term) {
+                break;
+            }
+            let replicated = self.members.keys()
+                .filter(|id| **id == self.id || self.progress.get(id).map(|p| p.match_index >= index).unwrap_or(false))
+                .count();
+            if replicated >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn step(&mut self, message: RaftMessage) -> Result<RaftMessage> {
+        let now = Instant::now();
+        // 最近还能收到leader消息时忽略投票请求, 避免被移除的节点不停发起选举干扰集群
+        if let Some(Body::RequestVote(_)) = message.body {
+            let leader_alive = self.leader.is_some()
+                && (self.role == Role::Leader || now < self.last_heard + ELECTION_TIMEOUT_MIN);
+            if leader_alive && message.term > self.log.term {
+                return Ok(self.message(Body::VoteResponse(VoteResponse { granted: false })));
+            }
+        }
+        if message.term > self.log.term {
+            self.become_follower(message.term, None)?;
+        }
+        match message.body {
+            Some(Body::RequestVote(request)) => {
+                let up_to_date = (request.last_log_term, request.last_log_index) >= (self.log.last_term(), self.log.last_index());
+                let can_vote = self.log.voted_for == 0 || self.log.voted_for == message.from;
+                let granted = message.term == self.log.term && can_vote && up_to_date;
+                if granted {
+                    self.log.set_hard_state(self.log.term, message.from)?;
+                    self.election_deadline = now + election_timeout();
+                }
+                Ok(self.message(Body::VoteResponse(VoteResponse { granted })))
+            }
+            Some(Body::AppendEntries(_)) | Some(Body::InstallSnapshot(_)) => {
+                if message.term < self.log.term {
+                    return Ok(self.append_response(false, 0));
+                }
+                if self.role != Role::Follower {
+                    self.become_follower(message.term, None)?;
+                }
+                self.leader = Some(message.from);
+                self.last_heard = now;
+                self.election_deadline = now + election_timeout();
+                match message.body {
+                    Some(Body::AppendEntries(request)) => self.append_entries(request),
+                    Some(Body::InstallSnapshot(request)) => self.install_snapshot(request),
+                    _ => unreachable!(),
+                }
+            }
+            _ => Err(anyhow!("unexpected raft message from {}", message.from)),
+        }
+    }
+
+    fn append_entries(&mut self, mut request: AppendEntries) -> Result<RaftMessage> {
+        // 已经压缩到快照中的记录都已经提交, 一定和leader一致, 直接跳过
+        let snapshot_index = self.log.snapshot_index();
+        if request.prev_log_index < snapshot_index {
+            let skip = snapshot_index - request.prev_log_index;
+            if (request.entries.len() as u64) < skip {
+                return Ok(self.append_response(true, request.prev_log_index + request.entries.len() as u64));
+            }
+            request.prev_log_term = request.entries[skip as usize - 1].term;
+            request.prev_log_index = snapshot_index;
+            request.entries.drain(..skip as usize);
+        }
+        let last_index = self.log.last_index();
+        if request.prev_log_index > last_index {
+            return Ok(self.append_response(false, last_index));
+        }
+        if self.log.term_at(request.prev_log_index) != Some(request.prev_log_term) {
+            return Ok(self.append_response(false, request.prev_log_index - 1));
+        }
+        let mut index = request.prev_log_index;
+        let mut entries = Vec::new();
+        let mut changed = false;
+        for entry in request.entries {
+            index += 1;
+            if entries.is_empty() {
+                match self.log.term_at(index) {
+                    Some(term) if term == entry.term => continue,
+                    // 和leader冲突的记录以及之后的记录都删除
+                    Some(_) => {
+                        self.log.truncate(index)?;
+                        self.waiters.retain(|i, _| *i < index);
+                        changed = true;
+                    }
+                    None => {}
+                }
+            }
+            entries.push(entry);
+        }
+        if !entries.is_empty() {
+            self.log.append(entries)?;
+            changed = true;
+        }
+        if changed {
+            self.refresh_members();
+        }
+        if request.leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(request.leader_commit.min(index));
+        }
+        Ok(self.append_response(true, index))
+    }
+
+    fn install_snapshot(&mut self, request: InstallSnapshot) -> Result<RaftMessage> {
+        // 已经提交的位置不需要快照
+        if request.last_index <= self.commit_index {
+            self.receiving = None;
+            return Ok(self.append_response(true, request.last_index));
+        }
+        let mut snapshot = match self.receiving.take() {
+            Some(snapshot) if (snapshot.last_index, snapshot.last_term) == (request.last_index, request.last_term) => snapshot,
+            _ => RaftSnapshot {
+                last_index: request.last_index,
+                last_term: request.last_term,
+                membership: request.membership,
+                entries: vec![],
+            },
+        };
+        // 不连续的数据丢掉, 让leader从已经收到的位置重发
+        if request.offset == snapshot.entries.len() as u64 {
+            snapshot.entries.extend(request.entries);
+            if request.done {
+                info!("raft node {} installs snapshot at {}", self.id, snapshot.last_index);
+                self.log.install(snapshot)?;
+                self.commit_index = request.last_index;
+                self.refresh_members();
+                return Ok(self.append_response(true, request.last_index));
+            }
+        }
+        let offset = snapshot.entries.len() as u64;
+        self.receiving = Some(snapshot);
+        Ok(self.message(Body::SnapshotResponse(SnapshotResponse { last_index: request.last_index, offset })))
+    }
+
+    fn append_response(&self, success: bool, match_index: u64) -> RaftMessage {
+        self.message(Body::AppendResponse(AppendResponse { success, match_index }))
+    }
+
+    fn on_reply(&mut self, peer: u64, message: RaftMessage) -> Result<Outgoing> {
+        if message.term > self.log.term {
+            self.become_follower(message.term, None)?;
+            self.election_deadline = Instant::now() + election_timeout();
+            return Ok(vec![]);
+        }
+        if message.term < self.log.term {
+            self.on_failure(peer);
+            return Ok(vec![]);
+        }
+        match message.body {
+            Some(Body::VoteResponse(VoteResponse { granted: true })) if self.role == Role::Candidate => {
+                self.votes.insert(peer);
+                let votes = self.votes.iter().filter(|id| self.members.contains_key(id)).count();
+                if votes >= self.quorum() {
+                    return self.become_leader();
+                }
+            }
+            Some(Body::AppendResponse(response)) if self.role == Role::Leader => {
+                if let Some(progress) = self.progress.get_mut(&peer) {
+                    progress.inflight = false;
+                    progress.snapshot_offset = 0;
+                    if response.success {
+                        progress.match_index = progress.match_index.max(response.match_index);
+                        progress.next_index = progress.match_index + 1;
+                    } else {
+                        progress.next_index = (progress.next_index - 1).min(response.match_index + 1).max(1);
+                    }
+                }
+                self.advance_commit();
+                return Ok(self.append_for(peer, false).into_iter().collect());
+            }
+            Some(Body::SnapshotResponse(response)) if self.role == Role::Leader => {
+                let snapshot_index = self.log.snapshot_index();
+                if let Some(progress) = self.progress.get_mut(&peer) {
+                    progress.inflight = false;
+                    // 快照在发送过程中又压缩过时从头开始
+                    progress.snapshot_offset = match response.last_index == snapshot_index {
+                        true => response.offset,
+                        false => 0,
+                    };
+                }
+                return Ok(self.append_for(peer, false).into_iter().collect());
+            }
+            _ => {}
+        }
+        Ok(vec![])
+    }
+
+    fn on_failure(&mut self, peer: u64) {
+        if let Some(progress) = self.progress.get_mut(&peer) {
+            progress.inflight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::OnceLock;
+    use tokio::net::TcpListener;
+    use crate::noise_codec::{generate_keypair, NOISE_CODEC};
+    use crate::limit::ConnectionLimits;
+    use crate::server::serve_connection;
+    use super::*;
+
+    struct TestNode {
+        state: Arc<ServerState>,
+        raft: Arc<RaftNode>,
+        addr: SocketAddr,
+    }
+
+    // 所有节点共用的密钥对: (私钥, 公钥)
+    fn node_keys() -> &'static (Vec<u8>, Vec<u8>) {
+        static KEYS: OnceLock<(Vec<u8>, Vec<u8>)> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            let keypair = generate_keypair().unwrap();
+            (keypair.private, keypair.public)
+        })
+    }
+
+    async fn start_node(id: u64, listener: TcpListener, members: BTreeMap<u64, String>) -> TestNode {
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::default());
+        let (private, public) = node_keys().clone();
+        let noise = Builder::new(NOISE_CODEC, true).local_private_key(private.clone()).remote_public_key(public);
+        let raft = RaftNode::start(id, members, RaftLog::memory(), &state, noise).unwrap();
+        let share = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let noise = Builder::new(NOISE_CODEC, false).local_private_key(private.clone());
+                tokio::spawn(serve_connection(stream, share.clone(), noise, ConnectionLimits::default()));
+            }
+        });
+        TestNode { state, raft, addr }
+    }
+
+    async fn start_cluster(count: u64) -> Vec<TestNode> {
+        let mut listeners = Vec::new();
+        for _ in 0..count {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let members: BTreeMap<u64, String> = listeners.iter().enumerate()
+            .map(|(i, l)| (i as u64 + 1, l.local_addr().unwrap().to_string()))
+            .collect();
+        let mut nodes = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            nodes.push(start_node(i as u64 + 1, listener, members.clone()).await);
+        }
+        nodes
+    }
+
+    async fn wait_leader(nodes: &[TestNode]) -> usize {
+        for _ in 0..300 {
+            if let Some(i) = nodes.iter().position(|n| n.raft.is_leader()) {
+                return i;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no leader elected");
+    }
+
+    // 直接读取节点本地的数据, 等待日志复制过来
+    async fn wait_value(node: &TestNode, key: &str) -> Vec<u8> {
+        for _ in 0..300 {
+            let response = node.state.handle(Request::new_get(key));
+            if response.code == 0 {
+                return response.value;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} not replicated to node {}", key, node.raft.id());
+    }
+
+    async fn connect(node: &TestNode) -> KvClient {
+        KvClient::connect(node.addr, Builder::new(NOISE_CODEC, true)).await.unwrap()
+    }
+
+    // 用节点的密钥连接, 可以变更成员
+    async fn connect_peer(node: &TestNode) -> KvClient {
+        let (private, public) = node_keys().clone();
+        KvClient::connect(node.addr, Builder::new(NOISE_CODEC, true).local_private_key(private).remote_public_key(public)).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_write_and_read() {
+        let nodes = start_cluster(3).await;
+        let leader = wait_leader(&nodes).await;
+        let client = connect(&nodes[leader]).await;
+        client.put("hello", b"world").await.unwrap();
+        assert_eq!(client.get("hello").await.unwrap(), Some(b"world".to_vec()));
+        for node in &nodes {
+            assert_eq!(wait_value(node, "hello").await, b"world");
+        }
+        // 其它节点返回leader的地址
+        let follower = connect(&nodes[(leader + 1) % 3]).await;
+        let error = follower.put("k", b"v").await.unwrap_err().to_string();
+        assert!(error.contains("421") && error.contains(&nodes[leader].addr.to_string()), "{}", error);
+
+        // 不是用节点的公钥连接的客户端不能发送raft消息
+        let message = RaftMessage { term: 100, from: 9, body: Some(Body::RequestVote(RequestVote::default())) };
+        let response = follower.request(Request::new_raft(message)).await.unwrap();
+        assert_eq!(response.code, 401);
+        assert!(nodes.iter().all(|node| node.raft.lock().log.term < 100));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_leader_failover() {
+        let mut nodes = start_cluster(3).await;
+        let leader = wait_leader(&nodes).await;
+        connect(&nodes[leader]).await.put("a", b"1").await.unwrap();
+        let old = nodes.remove(leader);
+        old.raft.stop();
+
+        let leader = wait_leader(&nodes).await;
+        let client = connect(&nodes[leader]).await;
+        assert_eq!(client.get("a").await.unwrap(), Some(b"1".to_vec()));
+        client.put("b", b"2").await.unwrap();
+        for node in &nodes {
+            assert_eq!(wait_value(node, "b").await, b"2");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_membership_change() {
+        let mut nodes = start_cluster(3).await;
+        let leader = wait_leader(&nodes).await;
+        let client = connect_peer(&nodes[leader]).await;
+        client.put("a", b"1").await.unwrap();
+
+        // 新节点不在初始成员中, 加入之后从leader复制全部日志
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let node = start_node(4, listener, nodes[0].raft.members()).await;
+        // 没有开启认证时不是用节点密钥连接的客户端不能变更成员
+        let other = connect(&nodes[leader]).await;
+        let error = other.add_member(4, &addr).await.unwrap_err().to_string();
+        assert!(error.contains("401"), "{}", error);
+        assert!(other.remove_member(nodes[(leader + 1) % 3].raft.id()).await.unwrap_err().to_string().contains("401"));
+        assert_eq!(nodes[leader].raft.members().len(), 3);
+        client.add_member(4, &addr).await.unwrap();
+        assert_eq!(wait_value(&node, "a").await, b"1");
+        assert_eq!(nodes[leader].raft.members().len(), 4);
+        nodes.push(node);
+
+        let removed = (leader + 1) % 3;
+        client.remove_member(nodes[removed].raft.id()).await.unwrap();
+        client.put("b", b"2").await.unwrap();
+        assert_eq!(wait_value(&nodes[3], "b").await, b"2");
+        assert_eq!(nodes[leader].raft.members().len(), 3);
+        assert!(!nodes[leader].raft.members().contains_key(&nodes[removed].raft.id()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot_catch_up() {
+        let nodes = start_cluster(3).await;
+        nodes.iter().for_each(|node| node.raft.set_snapshot_threshold(5));
+        let leader = wait_leader(&nodes).await;
+        let client = connect_peer(&nodes[leader]).await;
+        for i in 0..20 {
+            client.put(&format!("k{}", i), b"v").await.unwrap();
+        }
+        client.request(Request::new_put_ex("t", b"v", 60_000)).await.unwrap();
+        assert!(nodes[leader].raft.lock().log.snapshot_index() > 0);
+
+        // 新节点需要的记录已经压缩, leader发送快照
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let node = start_node(4, listener, nodes[0].raft.members()).await;
+        client.add_member(4, &addr).await.unwrap();
+        assert_eq!(wait_value(&node, "k0").await, b"v");
+        assert_eq!(wait_value(&node, "k19").await, b"v");
+        assert!(node.state.handle(Request::new_ttl("t")).ttl > 0);
+        assert_eq!(node.raft.members().len(), 4);
+        assert!(node.raft.lock().log.snapshot_index() > 0);
+        client.put("after", b"1").await.unwrap();
+        assert_eq!(wait_value(&node, "after").await, b"1");
+    }
+}
@@ -0,0 +1,146 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use crate::error::KvError;
+use crate::noise_codec::{self, Builder};
+use crate::protobuf::*;
+use crate::server::Writer;
+use crate::service::ServerState;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// leader端处理follower的复制请求
+/// 先发送follower缺少的记录(backlog中的修改或者全量快照), 然后持续推送新的修改直到连接断开
+/// 消息由连接的Writer写入, 排队满了之后等待, 跟不上的follower会被断开
+pub async fn serve_follower<S>(stream: &mut S, writer: &Writer, state: &ServerState, request: RequestReplicate, id: u64) -> Result<()>
+    where S: Stream<Item = Result<BytesMut>> + Unpin
+{
+    if state.is_follower() {
+        let error = KvError::InvalidCommand("cannot replicate from a follower".into());
+        writer.send(Response { id, ..error.into() }).await?;
+        return writer.flush().await;
+    }
+    let (messages, mut rx) = match state.replicate_from(&request.replication_id, request.offset) {
+        Ok(result) => result,
+        Err(e) => {
+            writer.send(Response { id, ..e.into() }).await?;
+            return writer.flush().await;
+        }
+    };
+    info!("follower starts replication from offset {}", request.offset);
+    for message in messages {
+        writer.send(Response { id, ..Response::with_replication(message) }).await?;
+    }
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Ok(message) => writer.send(Response { id, ..Response::with_replication(message) }).await?,
+                // follower跟不上时断开, 重连之后从backlog补齐
+                Err(RecvError::Lagged(count)) => return Err(anyhow!("follower lagged behind {} messages", count)),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // follower不会再发送请求, 读到的结果只用来发现连接断开
+            frame = stream.next() => if !matches!(frame, Some(Ok(_))) {
+                info!("follower disconnected");
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// follower端持续从leader复制, 断开之后按退避时间重连, 从已经同步到的位置继续
+pub async fn follow(leader: String, noise: Builder, state: Arc<ServerState>) {
+    let mut replication_id = String::new();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match sync_from(&leader, noise.clone(), &state, &mut replication_id, &mut backoff).await {
+            Ok(_) => warn!("leader [{}] closed the replication", leader),
+            Err(e) => warn!("replication from [{}] failed: {:?}", leader, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn sync_from(leader: &str, noise: Builder, state: &ServerState, replication_id: &mut String, backoff: &mut Duration) -> Result<()> {
+    let stream = TcpStream::connect(leader).await?;
+    let mut framed = noise.new_framed(stream)?;
+    noise_codec::handshake(&mut framed).await?;
+    *backoff = MIN_BACKOFF;
+    let offset = state.offset();
+    framed.send(Request::new_replicate(replication_id.as_str(), offset).with_id(1).into()).await?;
+    info!("replicating from [{}] at offset {}", leader, offset);
+    while let Some(frame) = framed.next().await {
+        let response: Response = frame?.try_into()?;
+        if response.code != 0 {
+            return Err(anyhow!("leader returned code {}: {}", response.code, response.message));
+        }
+        let replication = response.replication.ok_or_else(|| anyhow!("missing replication in response"))?;
+        if replication.reset {
+            info!("full resync from [{}] at offset {}", leader, replication.offset);
+        }
+        replication_id.clone_from(&replication.replication_id);
+        state.apply_replication(replication)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use crate::client::KvClient;
+    use crate::noise_codec::NOISE_CODEC;
+    use crate::limit::ConnectionLimits;
+    use crate::server::serve_connection;
+    use super::*;
+
+    async fn start_server(state: Arc<ServerState>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_connection(stream, state.clone(), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+            }
+        });
+        addr
+    }
+
+    async fn wait_for(client: &KvClient, key: &str) -> Option<Vec<u8>> {
+        for _ in 0..100 {
+            if let Some(value) = client.get(key).await.unwrap() {
+                return Some(value);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_follower_replicates() {
+        let leader = Arc::new(ServerState::default());
+        // follower启动之前的数据通过快照同步
+        leader.handle(Request::new_put("old", b"1"));
+        let leader_addr = start_server(leader.clone()).await;
+        let follower = Arc::new(ServerState::default());
+        follower.set_follower(true);
+        tokio::spawn(follow(leader_addr.to_string(), Builder::new(NOISE_CODEC, true), follower.clone()));
+        let follower_addr = start_server(follower.clone()).await;
+
+        let client = KvClient::connect(follower_addr, Builder::new(NOISE_CODEC, true)).await.unwrap();
+        assert_eq!(wait_for(&client, "old").await, Some(b"1".to_vec()));
+        leader.handle(Request::new_put("new", b"2"));
+        assert_eq!(wait_for(&client, "new").await, Some(b"2".to_vec()));
+        assert_eq!(follower.offset(), leader.offset());
+
+        let error = client.put("k", b"v").await.unwrap_err();
+        assert!(error.to_string().contains("403"));
+    }
+}
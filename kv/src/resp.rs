@@ -0,0 +1,672 @@
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use crate::acl::User;
+use crate::limit::{idle, ConnectionLimits};
+use crate::protobuf::*;
+use crate::server::execute;
+use crate::service::ServerState;
+
+// 单个参数和参数个数的上限, 防止恶意的长度把内存耗尽
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// RESP协议的返回值, RESP2下Null和Map会转换成RESP2的表示
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    fn ok() -> Self {
+        RespValue::Simple("OK".to_owned())
+    }
+
+    fn error(message: impl std::fmt::Display) -> Self {
+        RespValue::Error(format!("ERR {}", message))
+    }
+
+    fn bulk(value: impl Into<Vec<u8>>) -> Self {
+        RespValue::Bulk(value.into())
+    }
+}
+
+/// 解码客户端发送的命令, 支持数组格式和telnet使用的inline格式, 按协议版本编码返回值
+#[derive(Debug)]
+pub struct RespCodec {
+    version: u8,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        RespCodec { version: 2 }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let parsed = match src[0] {
+            b'*' => parse_array(src)?,
+            _ => parse_inline(src),
+        };
+        Ok(parsed.map(|(args, len)| {
+            src.advance(len);
+            args
+        }))
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> Result<()> {
+        encode_value(&item, self.version, dst);
+        Ok(())
+    }
+}
+
+fn encode_value(value: &RespValue, version: u8, dst: &mut BytesMut) {
+    match value {
+        RespValue::Simple(s) => dst.put_slice(format!("+{}\r\n", s).as_bytes()),
+        RespValue::Error(s) => dst.put_slice(format!("-{}\r\n", s).as_bytes()),
+        RespValue::Integer(i) => dst.put_slice(format!(":{}\r\n", i).as_bytes()),
+        RespValue::Bulk(b) => {
+            dst.put_slice(format!("${}\r\n", b.len()).as_bytes());
+            dst.put_slice(b);
+            dst.put_slice(b"\r\n");
+        }
+        RespValue::Null if version >= 3 => dst.put_slice(b"_\r\n"),
+        RespValue::Null => dst.put_slice(b"$-1\r\n"),
+        RespValue::Array(items) => {
+            dst.put_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode_value(item, version, dst);
+            }
+        }
+        RespValue::Map(pairs) => {
+            // RESP2没有map, 展开成key value交替的数组
+            match version >= 3 {
+                true => dst.put_slice(format!("%{}\r\n", pairs.len()).as_bytes()),
+                false => dst.put_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes()),
+            }
+            for (k, v) in pairs {
+                encode_value(k, version, dst);
+                encode_value(v, version, dst);
+            }
+        }
+    }
+}
+
+// 返回一行的内容(不含\r\n)和下一行开始的位置
+fn read_line(buf: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = buf[start..].windows(2).position(|w| w == b"\r\n")? + start;
+    Some((&buf[start..end], end + 2))
+}
+
+fn parse_len(line: &[u8]) -> Result<i64> {
+    std::str::from_utf8(line)?.parse().map_err(|_| anyhow!("invalid length"))
+}
+
+// *<n>\r\n 之后是n个 $<len>\r\n<data>\r\n, 数据不完整时返回None
+fn parse_array(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let (line, mut pos) = match read_line(buf, 0) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = parse_len(&line[1..])?;
+    if count < 0 || count as usize > MAX_ARGS {
+        return Err(anyhow!("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity((count as usize).min(64));
+    for _ in 0..count {
+        let (line, next) = match read_line(buf, pos) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err(anyhow!("expected '$', got '{}'", String::from_utf8_lossy(line)));
+        }
+        let len = parse_len(&line[1..])?;
+        if len < 0 || len as usize > MAX_BULK_LEN {
+            return Err(anyhow!("invalid bulk length"));
+        }
+        let end = next + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(anyhow!("bulk string is not terminated by CRLF"));
+        }
+        args.push(buf[next..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+// inline命令按空白切分, 兼容只发送\n的客户端
+fn parse_inline(buf: &[u8]) -> Option<(Vec<Vec<u8>>, usize)> {
+    let end = buf.iter().position(|b| *b == b'\n')?;
+    let args = buf[..end].split(|b| b.is_ascii_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| arg.to_vec())
+        .collect();
+    Some((args, end + 1))
+}
+
+/// 处理一个RESP客户端连接, 命令按顺序执行, 所以pipeline的返回值也是按顺序的
+/// RESP连接不加密, 只应该监听在可信的网络上
+pub async fn serve_resp<T>(stream: T, state: Arc<ServerState>, limits: ConnectionLimits) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let _connection = state.metrics().connection("resp");
+    let mut framed = Framed::new(stream, RespCodec::default());
+    let mut shutdown = state.shutdown_signal();
+    // 开启认证时AUTH成功之后的用户
+    let mut user: Option<Arc<User>> = None;
+    loop {
+        // 服务端退出时不再读取新的命令, 正在执行的命令已经返回
+        let deadline = limits.idle_timeout.map(|timeout| Instant::now() + timeout);
+        let frame = tokio::select! {
+            frame = framed.next() => frame,
+            _ = shutdown.recv() => break,
+            _ = idle(deadline) => break,
+        };
+        let args = match frame {
+            Some(Ok(args)) => args,
+            None => break,
+            Some(Err(e)) => {
+                // 协议错误之后无法再对齐命令的边界, 只能断开
+                framed.send(RespValue::Error(format!("ERR Protocol error: {}", e))).await?;
+                break;
+            }
+        };
+        let name = match args.first() {
+            Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+            None => continue,
+        };
+        if !limits.allow() {
+            framed.send(RespValue::error("too many requests")).await?;
+            continue;
+        }
+        let reply = match name.as_str() {
+            "QUIT" => {
+                framed.send(RespValue::ok()).await?;
+                break;
+            }
+            "HELLO" => match hello(&args[1..], framed.codec().version) {
+                Ok((version, reply)) => {
+                    framed.codec_mut().version = version;
+                    reply
+                }
+                Err(reply) => reply,
+            },
+            "AUTH" => match auth(&state, &args[1..]) {
+                Ok(authenticated) => {
+                    user = Some(authenticated);
+                    RespValue::ok()
+                }
+                Err(reply) => reply,
+            },
+            // 和redis一样, 认证之前只能执行AUTH/HELLO/QUIT
+            _ if state.acl().is_some() && user.is_none() => RespValue::Error("NOAUTH Authentication required.".to_owned()),
+            _ => command(&state, user.as_deref(), &name, &args[1..]).await.unwrap_or_else(|e| e),
+        };
+        framed.send(reply).await?;
+    }
+    Ok(())
+}
+
+// AUTH [username] password, 只有密码时用户名是default
+fn auth(state: &ServerState, args: &[Vec<u8>]) -> Result<Arc<User>, RespValue> {
+    arity("AUTH", args, 1, Some(2))?;
+    let acl = state.acl()
+        .ok_or_else(|| RespValue::error("AUTH called without any password configured for the default user"))?;
+    let (name, password) = match args {
+        [password] => ("default".into(), String::from_utf8_lossy(password)),
+        [name, password] => (String::from_utf8_lossy(name), String::from_utf8_lossy(password)),
+        _ => unreachable!(),
+    };
+    acl.authenticate(&name, &password)
+        .ok_or_else(|| RespValue::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned()))
+}
+
+// HELLO [protover], 不带版本时保持当前的版本
+fn hello(args: &[Vec<u8>], current: u8) -> Result<(u8, RespValue), RespValue> {
+    let version = match args.first() {
+        None => current,
+        Some(v) => match v.as_slice() {
+            b"2" => 2,
+            b"3" => 3,
+            _ => return Err(RespValue::Error("NOPROTO unsupported protocol version".to_owned())),
+        },
+    };
+    let reply = RespValue::Map(vec![
+        (RespValue::bulk("server"), RespValue::bulk("kv")),
+        (RespValue::bulk("version"), RespValue::bulk(env!("CARGO_PKG_VERSION"))),
+        (RespValue::bulk("proto"), RespValue::Integer(version as i64)),
+        (RespValue::bulk("id"), RespValue::Integer(0)),
+        (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+        (RespValue::bulk("role"), RespValue::bulk("master")),
+        (RespValue::bulk("modules"), RespValue::Array(vec![])),
+    ]);
+    Ok((version, reply))
+}
+
+fn string(arg: &[u8]) -> Result<String, RespValue> {
+    String::from_utf8(arg.to_vec()).map_err(|_| RespValue::error("key must be valid utf-8"))
+}
+
+fn integer(arg: &[u8]) -> Result<u64, RespValue> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| RespValue::error("value is not an integer or out of range"))
+}
+
+fn signed(arg: &[u8]) -> Result<i64, RespValue> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| RespValue::error("value is not an integer or out of range"))
+}
+
+fn arity(name: &str, args: &[Vec<u8>], min: usize, max: Option<usize>) -> Result<(), RespValue> {
+    if args.len() < min || max.map(|max| args.len() > max).unwrap_or(false) {
+        return Err(RespValue::error(format!("wrong number of arguments for '{}' command", name.to_ascii_lowercase())));
+    }
+    Ok(())
+}
+
+// kv的错误转换成RESP的错误, 404由各个命令自己处理
+fn check(response: Response) -> Result<Response, RespValue> {
+    match response.code {
+        0 | 404 => Ok(response),
+        _ => Err(RespValue::error(response.message)),
+    }
+}
+
+async fn request(state: &ServerState, user: Option<&User>, request: Request) -> Result<Response, RespValue> {
+    check(execute_as(state, user, request).await)
+}
+
+// 开启认证时先检查用户的权限, 没有权限的错误和执行的结果一样通过Response返回
+async fn execute_as(state: &ServerState, user: Option<&User>, request: Request) -> Response {
+    if let Some(Err(e)) = user.map(|user| user.check(&request)) {
+        return e.into();
+    }
+    execute(state, request).await
+}
+
+async fn command(state: &ServerState, user: Option<&User>, name: &str, args: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let reply = match name {
+        "PING" => {
+            arity(name, args, 0, Some(1))?;
+            match args.first() {
+                Some(message) => RespValue::bulk(message.clone()),
+                None => RespValue::Simple("PONG".to_owned()),
+            }
+        }
+        "ECHO" => {
+            arity(name, args, 1, Some(1))?;
+            RespValue::bulk(args[0].clone())
+        }
+        // 只有一个库, 客户端的连接设置都直接返回成功
+        "SELECT" => {
+            arity(name, args, 1, Some(1))?;
+            match args[0].as_slice() {
+                b"0" => RespValue::ok(),
+                _ => return Err(RespValue::error("DB index is out of range")),
+            }
+        }
+        "CLIENT" => RespValue::ok(),
+        "COMMAND" => RespValue::Array(vec![]),
+        "GET" => {
+            arity(name, args, 1, Some(1))?;
+            let response = request(state, user, Request::new_get(&string(&args[0])?)).await?;
+            match response.code {
+                404 => RespValue::Null,
+                _ => RespValue::Bulk(response.value),
+            }
+        }
+        "SET" => {
+            arity(name, args, 2, None)?;
+            let key = string(&args[0])?;
+            let ttl = match &args[2..] {
+                [] => 0,
+                [option, value] => {
+                    let unit = match String::from_utf8_lossy(option).to_ascii_uppercase().as_str() {
+                        "EX" => 1000,
+                        "PX" => 1,
+                        _ => return Err(RespValue::error("syntax error")),
+                    };
+                    // ttl为0在kv中表示不过期, 和redis一样拒绝
+                    match signed(value)? {
+                        ttl if ttl <= 0 => return Err(RespValue::error("invalid expire time in 'set' command")),
+                        ttl => (ttl as u64).saturating_mul(unit),
+                    }
+                }
+                _ => return Err(RespValue::error("syntax error")),
+            };
+            request(state, user, Request::new_put_ex(&key, &args[1], ttl)).await?;
+            RespValue::ok()
+        }
+        "SETNX" => {
+            arity(name, args, 2, Some(2))?;
+            let response = execute_as(state, user, Request::new_put_if_absent(&string(&args[0])?, &args[1], 0)).await;
+            match response.code {
+                409 => RespValue::Integer(0),
+                _ => RespValue::Integer(check(response).map(|_| 1)?),
+            }
+        }
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let delta = match name {
+                "INCR" | "DECR" => {
+                    arity(name, args, 1, Some(1))?;
+                    1
+                }
+                _ => {
+                    arity(name, args, 2, Some(2))?;
+                    signed(&args[1])?
+                }
+            };
+            let delta = match name {
+                "DECR" | "DECRBY" => delta.checked_neg().ok_or_else(|| RespValue::error("decrement would overflow"))?,
+                _ => delta,
+            };
+            let response = request(state, user, Request::new_incr(&string(&args[0])?, delta)).await?;
+            RespValue::Integer(response.number)
+        }
+        "DEL" | "EXISTS" => {
+            arity(name, args, 1, None)?;
+            let mut count = 0;
+            for key in args {
+                let key = string(key)?;
+                let req = match name {
+                    "DEL" => Request::new_delete(&key),
+                    _ => Request::new_exists(&key),
+                };
+                if request(state, user, req).await?.code == 0 {
+                    count += 1;
+                }
+            }
+            RespValue::Integer(count)
+        }
+        "KEYS" => {
+            arity(name, args, 1, Some(1))?;
+            let pattern = string(&args[0])?;
+            // 用第一个通配符之前的部分作为前缀查询, 再按完整的模式过滤
+            let prefix: String = pattern.chars().take_while(|c| !matches!(c, '*' | '?' | '[' | '\\')).collect();
+            let response = request(state, user, Request::new_keys(&prefix)).await?;
+            RespValue::Array(response.pairs.into_iter()
+                .filter(|pair| glob_match(pattern.as_bytes(), pair.key.as_bytes()))
+                .map(|pair| RespValue::bulk(pair.key))
+                .collect())
+        }
+        "MGET" => {
+            arity(name, args, 1, None)?;
+            let keys = args.iter().map(|key| string(key)).collect::<Result<Vec<_>, _>>()?;
+            let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+            let mut pairs = request(state, user, Request::new_get_all(&keys)).await?.pairs.into_iter().peekable();
+            // 不存在的key不在结果中, 按顺序补上Null
+            let mut values = Vec::with_capacity(keys.len());
+            for key in keys {
+                match pairs.next_if(|pair| pair.key == key) {
+                    Some(pair) => values.push(RespValue::Bulk(pair.value)),
+                    None => values.push(RespValue::Null),
+                }
+            }
+            RespValue::Array(values)
+        }
+        "MSET" => {
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return Err(RespValue::error("wrong number of arguments for 'mset' command"));
+            }
+            let mut pairs = Vec::with_capacity(args.len() / 2);
+            for chunk in args.chunks(2) {
+                pairs.push(Kvpair::new(string(&chunk[0])?, chunk[1].clone()));
+            }
+            request(state, user, Request::new_put_all(pairs)).await?;
+            RespValue::ok()
+        }
+        "EXPIRE" | "PEXPIRE" => {
+            arity(name, args, 2, Some(2))?;
+            let ttl = match name {
+                "EXPIRE" => integer(&args[1])?.saturating_mul(1000),
+                _ => integer(&args[1])?,
+            };
+            let response = request(state, user, Request::new_expire(&string(&args[0])?, ttl)).await?;
+            RespValue::Integer((response.code == 0) as i64)
+        }
+        "TTL" | "PTTL" => {
+            arity(name, args, 1, Some(1))?;
+            let response = request(state, user, Request::new_ttl(&string(&args[0])?)).await?;
+            // 和redis一样, key不存在返回-2, 没有过期时间返回-1
+            let ttl = match (response.code, response.ttl) {
+                (404, _) => -2,
+                (_, ttl) if ttl < 0 => -1,
+                (_, ttl) if name == "TTL" => (ttl + 500) / 1000,
+                (_, ttl) => ttl,
+            };
+            RespValue::Integer(ttl)
+        }
+        "PERSIST" => {
+            arity(name, args, 1, Some(1))?;
+            let key = string(&args[0])?;
+            // redis只有在去掉了过期时间时才返回1
+            let ttl = request(state, user, Request::new_ttl(&key)).await?;
+            if ttl.code != 0 || ttl.ttl < 0 {
+                return Ok(RespValue::Integer(0));
+            }
+            let response = request(state, user, Request::new_persist(&key)).await?;
+            RespValue::Integer((response.code == 0) as i64)
+        }
+        _ => return Err(RespValue::error(format!("unknown command '{}'", name.to_ascii_lowercase()))),
+    };
+    Ok(reply)
+}
+
+// redis的glob: * ? [abc] [a-z] [^a] 和\转义
+// 遇到*时记住位置, 之后匹配失败就回到最近的*让它多匹配一个字符, 最坏是O(pattern * text)
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // 最近一个*之后的pattern位置, 以及这个*已经匹配到的text位置
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// 用pattern中从p开始的一项(*之外)匹配一个字符, 匹配时返回下一项的位置
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let rest = &pattern[p..];
+    match rest.first()? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let end = match rest.iter().skip(1).position(|c| *c == b']') {
+                Some(i) => i + 1,
+                // 没有闭合的[当作普通字符
+                None => return (c == b'[').then_some(p + 1),
+            };
+            let (negate, class) = match rest.get(1) {
+                Some(b'^') => (true, &rest[2..end]),
+                _ => (false, &rest[1..end]),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some(p + end + 1)
+        }
+        b'\\' if rest.len() > 1 => (rest[1] == c).then_some(p + 2),
+        expected => (*expected == c).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::Acl;
+    use crate::config::{AuthConfig, UserConfig};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    #[test]
+    fn test_decode_commands() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$5\r\nhel"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.put_slice(b"lo\r\nPING\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(vec![b"GET".to_vec(), b"hello".to_vec()]));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(vec![b"PING".to_vec()]));
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut BytesMut::from(&b"*1\r\n:1\r\n"[..])).is_err());
+    }
+
+    #[test]
+    fn test_encode_versions() {
+        let value = RespValue::Array(vec![RespValue::Null, RespValue::Map(vec![(RespValue::bulk("a"), RespValue::Integer(1))])]);
+        let mut buf = BytesMut::new();
+        RespCodec { version: 2 }.encode(value.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"*2\r\n$-1\r\n*2\r\n$1\r\na\r\n:1\r\n");
+        let mut buf = BytesMut::new();
+        RespCodec { version: 3 }.encode(value, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"*2\r\n_\r\n%1\r\n$1\r\na\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*c", b"axxbyybc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyyb"));
+        assert!(glob_match(b"[abc", b"[abc"));
+        // 回溯的写法在这里是指数级的
+        let text = vec![b'a'; 100];
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(glob_match(b"*a*a*a*a*a*a*a*a*a*a*", &text));
+    }
+
+    #[tokio::test]
+    async fn test_serve_resp() {
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(serve_resp(server, Arc::new(ServerState::default()), ConnectionLimits::default()));
+        // pipeline发送, 按顺序返回
+        let commands = concat!(
+            "*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n",
+            "GET hello\r\n",
+            "GET missing\r\n",
+            "*3\r\n$6\r\nEXPIRE\r\n$5\r\nhello\r\n$2\r\n60\r\n",
+            "TTL hello\r\n",
+            "MGET hello missing\r\n",
+            "KEYS h*\r\n",
+            "INCRBY n 5\r\n",
+            "DECR n\r\n",
+            "SETNX n 1\r\n",
+            "INCR hello\r\n",
+            "DEL hello missing\r\n",
+            "HELLO 3\r\n",
+            "GET hello\r\n",
+            "QUIT\r\n",
+        );
+        client.write_all(commands.as_bytes()).await.unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let expected = concat!(
+            "+OK\r\n",
+            "$5\r\nworld\r\n",
+            "$-1\r\n",
+            ":1\r\n",
+            ":60\r\n",
+            "*2\r\n$5\r\nworld\r\n$-1\r\n",
+            "*1\r\n$5\r\nhello\r\n",
+            ":5\r\n",
+            ":4\r\n",
+            ":0\r\n",
+            "-ERR invalid command: value of hello is not an integer\r\n",
+            ":1\r\n",
+        );
+        assert!(output.starts_with(expected), "{}", output);
+        assert!(output.ends_with("_\r\n+OK\r\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_set_invalid_expire() {
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(serve_resp(server, Arc::new(ServerState::default()), ConnectionLimits::default()));
+        let commands = "SET k v EX 0\r\nSET k v PX 0\r\nSET k v EX -1\r\nGET k\r\nSET k v PX 60000\r\nGET k\r\nQUIT\r\n";
+        client.write_all(commands.as_bytes()).await.unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        let error = "-ERR invalid expire time in 'set' command\r\n";
+        let expected = format!("{}{}{}$-1\r\n+OK\r\n$1\r\nv\r\n+OK\r\n", error, error, error);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_resp_auth() {
+        let auth = AuthConfig {
+            users: vec![UserConfig { name: "default".into(), password: Some("secret".into()), write: vec!["a/".into()], ..Default::default() }],
+        };
+        let state = Arc::new(ServerState::default().with_acl(Acl::new(&auth).unwrap()));
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(serve_resp(server, state, ConnectionLimits::default()));
+        let commands = "GET a/1\r\nAUTH wrong\r\nAUTH secret\r\nSET a/1 x\r\nGET b/1\r\nQUIT\r\n";
+        client.write_all(commands.as_bytes()).await.unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        let expected = concat!(
+            "-NOAUTH Authentication required.\r\n",
+            "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+            "+OK\r\n",
+            "+OK\r\n",
+            "-ERR permission denied: user default cannot read b/1\r\n",
+            "+OK\r\n",
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
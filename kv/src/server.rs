@@ -1,82 +1,445 @@
-mod protobuf;
-mod noise_codec;
-
 use std::sync::Arc;
 use std::convert::TryInto;
-use dashmap::DashMap;
-use tracing::{info, Level};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use protobuf::*;
-use protobuf::request::*;
-use anyhow::Result;
-use tokio_util::codec::LengthDelimitedCodec;
-use tokio::net::{TcpListener};
-use futures::{StreamExt, SinkExt};
-
-#[derive(Debug)]
-struct ServerState {
-    state: DashMap<String, Vec<u8>>,
+use std::future::Future;
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use futures::future::{join_all, Shared};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::debug;
+use crate::acl::Identity;
+use crate::error::KvError;
+use crate::limit::{idle, ConnectionLimits};
+use crate::protobuf::*;
+use crate::protobuf::request::Command;
+use crate::noise_codec::{self, Builder};
+use crate::replication;
+use crate::service::{ServerState, Session};
+use crate::tls::TlsServer;
+
+/// 一个连接上同时处理的最大请求数, 超过之后暂停读取新的请求
+pub const MAX_IN_FLIGHT: usize = 128;
+
+/// 握手之后按消息收发的连接, noise和TLS的连接都实现了这个trait
+pub trait Transport: Stream<Item = Result<BytesMut>> + Sink<Bytes, Error = anyhow::Error> + Unpin {}
+
+impl<T> Transport for T
+    where T: Stream<Item = Result<BytesMut>> + Sink<Bytes, Error = anyhow::Error> + Unpin {}
+
+/// 处理一个客户端连接, 先完成noise握手, 然后循环处理请求直到连接断开
+/// 服务端退出时不再读取新的请求, 已经在处理的请求的响应写回之后关闭连接
+/// limits中的空闲超时和限流只对这个连接生效, 超过限流的请求返回429
+pub async fn serve_connection<T>(stream: T, state: Arc<ServerState>, noise: Builder, limits: ConnectionLimits) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    // 握手之后的数据都是加密的
+    let _connection = state.metrics().connection("kv");
+    let mut stream = noise.new_framed(stream)?;
+    handshake(&limits, noise_codec::handshake(&mut stream)).await?;
+    let identity = stream.codec().remote_static().map(|key| Identity::PublicKey(key.to_vec()));
+    serve_transport(stream, state, identity, limits).await
 }
 
-impl ServerState {
-    pub fn new() -> Self {
-        ServerState {
-            state: DashMap::new(),
-        }
+/// 和serve_connection一样, 但是用TLS代替noise, 客户端证书中的身份会交给Session
+pub async fn serve_tls_connection<T>(stream: T, state: Arc<ServerState>, tls: TlsServer, limits: ConnectionLimits) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let _connection = state.metrics().connection("kv");
+    let (stream, identity) = handshake(&limits, tls.accept(stream)).await?;
+    debug!("tls client identity: {:?}", identity);
+    serve_transport(stream, state, identity.map(Identity::Certificate), limits).await
+}
+
+// 握手也算在空闲时间里, 避免连接上之后什么都不发
+async fn handshake<F: Future<Output = Result<R>>, R>(limits: &ConnectionLimits, handshake: F) -> Result<R> {
+    match limits.idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake).await
+            .map_err(|_| anyhow!("handshake timed out"))?,
+        None => handshake.await,
     }
 }
 
-impl Default for ServerState {
-    fn default() -> Self {
-        Self::new()
+// 请求执行完之后drop掉对应的oneshot::Sender, 等待它的请求就可以开始执行
+type Done = Shared<oneshot::Receiver<()>>;
+
+// 同一个连接上请求的执行顺序: 只读请求等之前的修改执行完, 修改等之前所有的请求执行完,
+// 所以连续的只读请求仍然并发执行, 同一个客户端总能读到自己之前的修改
+#[derive(Default)]
+struct RequestOrder {
+    last_write: Option<Done>,
+    // 上一个修改之后的只读请求
+    reads: Vec<Done>,
+}
+
+impl RequestOrder {
+    // 返回执行完之后需要drop的Sender, 和开始执行之前需要等待的future
+    fn next(&mut self, read_only: bool) -> (oneshot::Sender<()>, impl Future<Output = ()>) {
+        let (tx, rx) = oneshot::channel();
+        let done = rx.shared();
+        let mut waits: Vec<Done> = self.last_write.iter().cloned().collect();
+        if read_only {
+            // 已经执行完的只读请求不需要再等待
+            self.reads.retain(|read| read.clone().now_or_never().is_none());
+            self.reads.push(done);
+        } else {
+            waits.append(&mut self.reads);
+            self.last_write = Some(done);
+        }
+        (tx, join_all(waits).map(|_| ()))
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // 日志初始化
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::LevelFilter::from(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let state = Arc::new(ServerState::new());
-    let addr = "0.0.0.0:8888";
-    info!("Starting server in [{:?}]", addr);
-    // tcp监听
-    let listener = TcpListener::bind(addr).await?;
-    loop {
-        // 阻塞等待连接
-        let (stream, socket_addr) = listener.accept().await?;
-        info!("accept a new connection: [{:?} accept]", socket_addr);
-        let share = state.clone();
-        tokio::spawn(async move {
-            // 解包
-            let mut stream = LengthDelimitedCodec::builder().length_field_length(2)
-                .new_framed(stream);
-            while let Some(Ok(buf)) = stream.next().await {
-                // 这个地方要指明类型，不然编译不通过
-                let request:Request = buf.try_into()?;
-                let response = match request.command {
-                    Some(Command::Get(RequestGet{key})) => {
-                        let value = share.state.get(&key);
-                        match value {
-                            None => Response::not_found(key),
-                            Some(v) => {
-                                Response::new(key, v.value().to_vec())
-                            }
-                        }
+/// 连接的写端, 由单独的任务把响应写入连接, 写被阻塞时读的一端仍然继续处理,
+/// 否则两端同时发送大的消息时都在等对方读取, 会互相卡住
+/// 每个排队的响应占用一个permit, 写入之后才释放, 所以排队的响应不会超过MAX_IN_FLIGHT
+#[derive(Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<(Response, OwnedSemaphorePermit)>,
+    permits: Arc<Semaphore>,
+}
+
+impl Writer {
+    /// 启动写入的任务, 订阅推送的消息也由这个任务写入, 写入失败时任务结束
+    /// 返回的watch记录最后一次写入的时间, 写入之后permit已经释放
+    pub fn spawn<S>(mut sink: S, mut messages: mpsc::Receiver<Response>) -> (Self, watch::Receiver<Instant>, JoinHandle<Result<()>>)
+        where S: Sink<Bytes, Error = anyhow::Error> + Unpin + Send + 'static
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Response, OwnedSemaphorePermit)>();
+        let (written_tx, written) = watch::channel(Instant::now());
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some((response, permit)) = rx.recv() => {
+                        sink.send(response.into()).await?;
+                        drop(permit);
                     }
-                    Some(Command::Put(ResponsePut{key, value})) => {
-                        share.state.insert(key.clone(), value.clone());
-                        Response::new(key, value)
+                    Some(message) = messages.recv() => sink.send(message.into()).await?,
+                    else => return Ok(()),
+                }
+                written_tx.send_replace(Instant::now());
+            }
+        });
+        (Writer { tx, permits: Arc::new(Semaphore::new(MAX_IN_FLIGHT)) }, written, task)
+    }
+
+    /// 还可以排队的响应数
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    /// 所有的响应都已经写入连接
+    pub fn is_idle(&self) -> bool {
+        self.available() == MAX_IN_FLIGHT
+    }
+
+    /// 为一个请求的响应预留位置, 没有位置时返回错误
+    pub fn reserve(&self) -> Result<OwnedSemaphorePermit> {
+        Ok(self.permits.clone().try_acquire_owned()?)
+    }
+
+    /// 把响应和预留的permit一起排队, 写入的任务已经结束时丢弃
+    pub fn queue(&self, response: Response, permit: OwnedSemaphorePermit) {
+        let _ = self.tx.send((response, permit));
+    }
+
+    /// 等待有位置之后排队
+    pub async fn send(&self, response: Response) -> Result<()> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        self.tx.send((response, permit)).map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// 等待排队的响应全部写入
+    pub async fn flush(&self) -> Result<()> {
+        let _permits = self.permits.acquire_many(MAX_IN_FLIGHT as u32).await?;
+        Ok(())
+    }
+}
+
+async fn serve_transport(stream: impl Transport + Send + 'static, state: Arc<ServerState>, identity: Option<Identity>, limits: ConnectionLimits) -> Result<()> {
+    // 订阅的消息通过这个通道推送到连接上
+    let (tx, rx) = mpsc::channel(128);
+    let mut session = Session::new(state.clone(), tx).with_identity(identity);
+    // 请求读取之后就占用一个permit, 响应写回连接后才释放,
+    // 所以同时在处理和等待写回的请求不会超过MAX_IN_FLIGHT
+    let (sink, mut stream) = stream.split();
+    let (writer, mut written, mut write_task) = Writer::spawn(sink, rx);
+    let mut shutdown = state.shutdown_signal();
+    let mut draining = shutdown.is_shutdown();
+    // 每次收发数据之后重新计算空闲的截止时间
+    let mut deadline = limits.idle_timeout.map(|timeout| Instant::now() + timeout);
+    let mut order = RequestOrder::default();
+    let result = loop {
+        let idle_now = writer.is_idle();
+        if draining && idle_now {
+            break Ok(());
+        }
+        tokio::select! {
+            // 没有permit时先不读新的请求
+            frame = stream.next(), if !draining && writer.available() > 0 => {
+                let buf = match frame {
+                    Some(Ok(buf)) => buf,
+                    _ => break Ok(()),
+                };
+                deadline = limits.idle_timeout.map(|timeout| Instant::now() + timeout);
+                let permit = writer.reserve()?;
+                // 解析失败时返回错误的响应, 不断开连接
+                let request: Result<Request, _> = buf.try_into();
+                let request = match request {
+                    Ok(request) => request,
+                    Err(e) => {
+                        writer.queue(Response::from(KvError::from(e)), permit);
+                        continue;
                     }
-                    _ => Response::default(),
                 };
-                stream.send(response.into()).await?;
+                let id = request.id;
+                // 集群节点之间的消息不限流, 否则会影响选举和复制, 没有通过认证的raft消息仍然限流
+                let peer = matches!(request.command, Some(Command::Raft(_))) && session.authorize(&request).is_ok();
+                if !peer && !limits.allow() {
+                    let mut response = Response::from(KvError::RateLimited("rate limit exceeded".into()));
+                    response.id = id;
+                    writer.queue(response, permit);
+                    continue;
+                }
+                // 开启认证时没有权限的请求直接返回错误
+                if let Err(e) = session.authorize(&request) {
+                    writer.queue(Response { id, ..e.into() }, permit);
+                    continue;
+                }
+                match request.command {
+                    // 复制的连接只用来推送修改, 交给replication处理
+                    Some(Command::Replicate(replicate)) => {
+                        // follower断开之后会重连, 退出时不需要等待复制的连接
+                        drop((shutdown, permit));
+                        break replication::serve_follower(&mut stream, &writer, &state, replicate, id).await;
+                    }
+                    // 订阅和认证需要修改session, 按顺序处理
+                    Some(Command::Subscribe(_)) | Some(Command::Unsubscribe(_)) | Some(Command::Auth(_)) => {
+                        let mut response = session.handle(request);
+                        response.id = id;
+                        writer.queue(response, permit);
+                    }
+                    // 其它请求并发处理, 响应的顺序和请求的顺序可能不一致, 但是执行的顺序见RequestOrder
+                    _ => {
+                        let (done, previous) = order.next(request.is_read_only());
+                        let state = state.clone();
+                        let writer = writer.clone();
+                        tokio::spawn(async move {
+                            previous.await;
+                            let mut response = execute(&state, request).await;
+                            drop(done);
+                            response.id = id;
+                            writer.queue(response, permit);
+                        });
+                    }
+                }
             }
-            Ok::<(), anyhow::Error>(())
-        });
+            _ = shutdown.recv(), if !draining => {
+                draining = true;
+            }
+            // 写入之后释放了permit, 重新检查是否可以继续读取或者已经空闲
+            Ok(()) = written.changed() => {
+                deadline = limits.idle_timeout.map(|timeout| *written.borrow() + timeout);
+            }
+            // 还有请求在处理时不算空闲
+            _ = idle(deadline), if idle_now => {
+                debug!("connection idle for {:?}, closing", limits.idle_timeout);
+                break Ok(());
+            }
+            result = &mut write_task => {
+                break result.map_err(|e| anyhow!(e)).and_then(|r| r);
+            }
+        }
+    };
+    write_task.abort();
+    result
+}
+
+/// 执行订阅之外的请求, 集群模式下由raft决定请求在哪里执行
+pub async fn execute(state: &ServerState, request: Request) -> Response {
+    let command = request.command_name();
+    let start = Instant::now();
+    let response = match state.cluster() {
+        Some(raft) => raft.handle(request).await,
+        None => state.handle(request),
+    };
+    state.metrics().observe(command, response.code, start.elapsed());
+    response
+}
+
+/// 等待SIGINT或者SIGTERM
+pub async fn wait_for_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::io::duplex;
+    use crate::acl::Acl;
+    use crate::config::{AuthConfig, RateLimitConfig, ServerConfig, UserConfig};
+    use crate::limit::Limiter;
+    use crate::noise_codec::NOISE_CODEC;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invalid_request_keeps_connection() {
+        let (a, b) = duplex(4096);
+        tokio::spawn(serve_connection(b, Arc::new(ServerState::default()), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        client.send(Bytes::from_static(&[0xff, 0xff, 0xff])).await.unwrap();
+        let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+        assert_eq!(response.code, 400);
+        assert!(response.message.starts_with("cannot decode request"));
+
+        client.send(Request::new_get("missing").into()).await.unwrap();
+        let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+        assert_eq!((response.code, response.message.as_str()), (404, "not found: missing"));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let (a, b) = duplex(4096);
+        tokio::spawn(serve_connection(b, Arc::new(ServerState::default()), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        // 不等待响应连续发送, 然后按id核对
+        for i in 1..=10u64 {
+            let key = format!("key{}", i);
+            client.send(Request::new_put(&key, key.as_bytes()).with_id(i).into()).await.unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 1..=10 {
+            let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+            assert_eq!(response.key, format!("key{}", response.id));
+            ids.push(response.id);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pipelined_read_your_writes() {
+        let (a, b) = duplex(65536);
+        tokio::spawn(serve_connection(b, Arc::new(ServerState::default()), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        // 同一个key交替写入和读取, 每次读取都能看到之前的写入
+        for i in 1..=50u64 {
+            client.send(Request::new_put("k", i.to_string().as_bytes()).with_id(i * 2 - 1).into()).await.unwrap();
+            client.send(Request::new_get("k").with_id(i * 2).into()).await.unwrap();
+        }
+        for _ in 0..100 {
+            let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+            if response.id.is_multiple_of(2) {
+                assert_eq!(response.value, (response.id / 2).to_string().as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let state = Arc::new(ServerState::default());
+        let (a, b) = duplex(4096);
+        let server = tokio::spawn(serve_connection(b, state.clone(), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        client.send(Request::new_put("a", b"1").with_id(1).into()).await.unwrap();
+        let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+        assert_eq!((response.id, response.code), (1, 0));
+        // 退出之后连接关闭, 不再处理新的请求
+        state.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), state.drained()).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert!(client.next().await.is_none());
+        assert!(state.flush().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_auth_and_acl() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = noise_codec::generate_keypair().unwrap();
+        std::fs::write(dir.path().join("node.pub"), &node.public).unwrap();
+        let auth = AuthConfig {
+            users: vec![
+                UserConfig { name: "team-a".into(), password: Some("secret".into()), write: vec!["a/".into()], ..Default::default() },
+                UserConfig { name: "node".into(), public_key: Some(dir.path().join("node.pub").to_str().unwrap().into()), admin: true, ..Default::default() },
+            ],
+        };
+        let state = Arc::new(ServerState::default().with_acl(Acl::new(&auth).unwrap()));
+        let (a, b) = duplex(4096);
+        tokio::spawn(serve_connection(b, state.clone(), Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        let requests = [
+            Request::new_put("a/1", b"1"),
+            Request::new_auth("team-a", "wrong"),
+            Request::new_auth("team-a", "secret"),
+            Request::new_put("a/1", b"1"),
+            Request::new_get("b/1"),
+        ];
+        let mut codes = Vec::new();
+        for request in requests {
+            client.send(request.into()).await.unwrap();
+            let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+            codes.push(response.code);
+        }
+        assert_eq!(codes, vec![401, 401, 0, 0, 403]);
+
+        // 公钥匹配的连接在握手之后就已经认证
+        let (a, b) = duplex(4096);
+        tokio::spawn(serve_connection(b, state, Builder::new(NOISE_CODEC, false), ConnectionLimits::default()));
+        let mut client = Builder::new(NOISE_CODEC, true).local_private_key(node.private).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+        client.send(Request::new_get("b/1").into()).await.unwrap();
+        let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+        assert_eq!(response.code, 404);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: 1,
+            rate_limit: Some(RateLimitConfig { requests_per_second: 1, burst: None }),
+            ..Default::default()
+        };
+        let limits = Limiter::new(&config).admit("127.0.0.1".parse().unwrap()).unwrap();
+        let (a, b) = duplex(4096);
+        let server = tokio::spawn(serve_connection(b, Arc::new(ServerState::default()), Builder::new(NOISE_CODEC, false), limits));
+        let mut client = Builder::new(NOISE_CODEC, true).new_framed(a).unwrap();
+        noise_codec::handshake(&mut client).await.unwrap();
+
+        client.send(Request::new_put("a", b"1").with_id(1).into()).await.unwrap();
+        client.send(Request::new_get("a").with_id(2).into()).await.unwrap();
+        let mut codes = Vec::new();
+        for _ in 0..2 {
+            let response: Response = client.next().await.unwrap().unwrap().try_into().unwrap();
+            codes.push((response.id, response.code));
+        }
+        codes.sort_unstable();
+        assert_eq!(codes, vec![(1, 0), (2, 429)]);
+        // 空闲超过1秒之后服务端关闭连接
+        tokio::time::timeout(Duration::from_secs(3), server).await.unwrap().unwrap().unwrap();
+        assert!(client.next().await.is_none());
     }
 }
@@ -0,0 +1,1048 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{broadcast, mpsc, watch};
+use prost::Message;
+use tracing::{debug, error, info};
+use crate::acl::{Acl, Identity, User};
+use crate::error::KvError;
+use crate::metrics::Metrics;
+use crate::protobuf::*;
+use crate::protobuf::request::*;
+use crate::raft::RaftNode;
+use crate::storage::{MemTable, Storage};
+use crate::topic::Broadcaster;
+use crate::ttl::{now_ms, Expirations};
+use crate::wal::Wal;
+
+/// 默认保留最近多少条修改记录, follower断开重连时可以从这里补齐
+pub const DEFAULT_BACKLOG: usize = 10000;
+// 补齐和快照时每条消息最多包含的记录数和字节数
+const REPLICATION_CHUNK: usize = 100;
+const REPLICATION_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+/// 服务端的共享状态, 所有连接通过它操作存储
+/// 读请求直接访问存储, 修改都会转换成LogEntry, 持有log的锁按顺序写入
+pub struct ServerState {
+    store: Box<dyn Storage>,
+    broadcaster: Broadcaster,
+    expirations: Expirations,
+    log: Mutex<LogState>,
+    // 每次启动随机生成, follower据此判断offset是否还有效
+    replication_id: String,
+    // 新的修改记录推送给所有follower
+    replication_tx: broadcast::Sender<Replication>,
+    // follower只接受leader同步过来的修改
+    follower: AtomicBool,
+    // 集群模式下的raft节点, 客户端的请求先交给它处理
+    cluster: OnceLock<Arc<RaftNode>>,
+    // 优雅退出的信号, 每个连接持有一个receiver
+    shutdown: watch::Sender<bool>,
+    metrics: Metrics,
+    // 不为空时连接需要认证, 请求按用户的权限检查
+    acl: Option<Acl>,
+}
+
+// 判断过期时使用的时间, reap为false时过期的key只当作不存在, 不会删除
+// 集群模式下删除过期的key也要写入raft日志, 所以只在执行日志时删除
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    now: u64,
+    reap: bool,
+}
+
+// 修改相关的状态, 都在同一把锁里面
+struct LogState {
+    wal: Option<Wal>,
+    // 已经写入的修改记录数量, follower上是从leader同步到的位置
+    offset: u64,
+    // 最近的修改记录, 最后一条的偏移量是offset
+    backlog: VecDeque<LogEntry>,
+    backlog_size: usize,
+}
+
+impl ServerState {
+    pub fn new(store: impl Storage) -> Self {
+        let (replication_tx, _) = broadcast::channel(1024);
+        // 已经存在的数据计入key的数量和大小, 之后由apply增量更新
+        let metrics = Metrics::new();
+        if let Ok(pairs) = store.iter() {
+            for pair in pairs {
+                metrics.add_data(1, (pair.key.len() + pair.value.len()) as i64);
+            }
+        }
+        // 恢复磁盘存储中保存的过期时间, 重启期间已经过期的key由reaper删除
+        let expirations = Expirations::new();
+        match store.deadlines() {
+            Ok(deadlines) => deadlines.into_iter().for_each(|(key, deadline)| expirations.set_at(&key, deadline)),
+            Err(e) => error!("failed to load deadlines: {:?}", e),
+        }
+        ServerState {
+            store: Box::new(store),
+            broadcaster: Broadcaster::new(),
+            expirations,
+            log: Mutex::new(LogState {
+                wal: None,
+                offset: 0,
+                backlog: VecDeque::new(),
+                backlog_size: DEFAULT_BACKLOG,
+            }),
+            replication_id: format!("{:x}-{:x}", now_ms(), std::process::id()),
+            replication_tx,
+            follower: AtomicBool::new(false),
+            cluster: OnceLock::new(),
+            shutdown: watch::channel(false).0,
+            metrics,
+            acl: None,
+        }
+    }
+
+    /// 开启预写日志, 先重放快照和日志恢复数据
+    pub fn with_wal(self, mut wal: Wal) -> Result<Self, KvError> {
+        for entry in wal.replay()? {
+            self.apply(&entry)?;
+        }
+        self.lock_log().wal = Some(wal);
+        Ok(self)
+    }
+
+    /// 开启认证和权限检查
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    pub fn acl(&self) -> Option<&Acl> {
+        self.acl.as_ref()
+    }
+
+    /// 设置保留的修改记录数量
+    pub fn with_backlog(self, size: usize) -> Self {
+        self.lock_log().backlog_size = size;
+        self
+    }
+
+    /// 作为follower运行, 拒绝客户端的修改
+    pub fn set_follower(&self, follower: bool) {
+        self.follower.store(follower, Ordering::Relaxed);
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.follower.load(Ordering::Relaxed)
+    }
+
+    /// 开启集群模式, 只能设置一次
+    pub fn set_cluster(&self, node: Arc<RaftNode>) -> Result<(), KvError> {
+        self.cluster.set(node).map_err(|_| KvError::Internal("cluster mode already enabled".into()))
+    }
+
+    pub fn cluster(&self) -> Option<&Arc<RaftNode>> {
+        self.cluster.get()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// 通知所有连接停止读取新的请求, 正在处理的请求写回之后连接关闭
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// 连接在处理完所有请求之前持有这个信号
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.shutdown.subscribe())
+    }
+
+    /// 等待所有的ShutdownSignal都被drop, 也就是所有的连接都已经退出
+    pub async fn drained(&self) {
+        self.shutdown.closed().await
+    }
+
+    /// 把wal和存储缓存的修改写到磁盘, 退出之前调用
+    pub fn flush(&self) -> Result<(), KvError> {
+        let mut log = self.lock_log();
+        if let Some(wal) = log.wal.as_mut() {
+            wal.sync()?;
+        }
+        Ok(self.store.flush()?)
+    }
+
+    pub fn replication_id(&self) -> &str {
+        &self.replication_id
+    }
+
+    /// 当前的复制偏移量
+    pub fn offset(&self) -> u64 {
+        self.lock_log().offset
+    }
+
+    /// 把当前的数据写成快照并清空日志, 没有开启日志时什么都不做
+    pub fn snapshot(&self) -> Result<(), KvError> {
+        let mut log = self.lock_log();
+        if let Some(wal) = log.wal.as_mut() {
+            let count = wal.snapshot(self.entries()?)?;
+            info!("snapshot {} keys", count);
+        }
+        Ok(())
+    }
+
+    // 当前的全部数据, 重放这些记录可以得到同样的数据
+    fn entries(&self) -> Result<impl Iterator<Item = LogEntry> + '_, KvError> {
+        Ok(self.store.iter()?.map(|pair| {
+            let expire_at = self.expirations.deadline(&pair.key).unwrap_or(0);
+            LogEntry::put(pair.key, pair.value, expire_at)
+        }))
+    }
+
+    /// 集群模式下压缩raft日志时保存的数据
+    pub(crate) fn snapshot_entries(&self) -> Result<Vec<LogEntry>, KvError> {
+        Ok(self.entries()?.collect())
+    }
+
+    /// 集群模式下用raft的快照替换全部数据
+    pub(crate) fn restore(&self, entries: &[LogEntry]) -> Result<(), KvError> {
+        let _log = self.lock_log();
+        let keys: Vec<String> = self.store.iter()?.map(|pair| pair.key).collect();
+        for key in keys {
+            self.apply(&LogEntry::delete(key))?;
+        }
+        for entry in entries {
+            self.apply(entry)?;
+        }
+        Ok(())
+    }
+
+    /// follower从offset开始复制, 返回需要先发送的记录和之后新修改的订阅
+    /// replication_id不一致或者offset已经不在backlog中时先发送全量的快照
+    pub fn replicate_from(&self, replication_id: &str, offset: u64)
+        -> Result<(Vec<Replication>, broadcast::Receiver<Replication>), KvError>
+    {
+        // 持有锁保证发送的记录和之后订阅到的修改是连续的
+        let log = self.lock_log();
+        let rx = self.replication_tx.subscribe();
+        let first = log.offset - log.backlog.len() as u64;
+        let mut messages = Vec::new();
+        if replication_id == self.replication_id && offset >= first && offset <= log.offset {
+            let entries = log.backlog.iter().skip((offset - first) as usize).cloned();
+            // 每条消息的offset是其中最后一条记录的位置
+            let mut offset = offset;
+            for chunk in chunks(entries) {
+                offset += chunk.len() as u64;
+                messages.push(self.replication(offset, false, chunk));
+            }
+        } else {
+            // 快照分成多条消息发送, 只有第一条需要清空follower的数据
+            let mut chunks = chunks(self.entries()?).into_iter();
+            messages.push(self.replication(log.offset, true, chunks.next().unwrap_or_default()));
+            messages.extend(chunks.map(|chunk| self.replication(log.offset, false, chunk)));
+        }
+        Ok((messages, rx))
+    }
+
+    fn replication(&self, offset: u64, reset: bool, entries: Vec<LogEntry>) -> Replication {
+        Replication {
+            replication_id: self.replication_id.clone(),
+            offset,
+            reset,
+            entries,
+        }
+    }
+
+    /// follower应用从leader同步过来的修改
+    pub fn apply_replication(&self, replication: Replication) -> Result<u64, KvError> {
+        let mut log = self.lock_log();
+        if replication.reset {
+            let keys: Vec<String> = self.store.iter()?.map(|pair| pair.key).collect();
+            for key in keys {
+                self.apply(&LogEntry::delete(key))?;
+            }
+        }
+        for entry in &replication.entries {
+            self.apply(entry)?;
+        }
+        log.offset = replication.offset;
+        Ok(log.offset)
+    }
+
+    fn lock_log(&self) -> MutexGuard<'_, LogState> {
+        // 持有锁的线程panic之后日志仍然是完整的, 可以继续使用
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // 修改先写日志再生效, 返回修改之前的值
+    fn write(&self, entry: LogEntry) -> Result<Option<Vec<u8>>, KvError> {
+        let mut log = self.lock_writable()?;
+        self.write_locked(&mut log, entry)
+    }
+
+    // 需要先读再写的命令持有这个锁, 保证读到的值在写入之前不会被修改
+    fn lock_writable(&self) -> Result<MutexGuard<'_, LogState>, KvError> {
+        if self.is_follower() {
+            return Err(KvError::ReadOnly("follower does not accept writes".into()));
+        }
+        Ok(self.lock_log())
+    }
+
+    // 持有锁时读取当前的值, 已经过期的key当作不存在
+    fn current_locked(&self, key: &str, clock: Clock) -> Result<Option<Vec<u8>>, KvError> {
+        if self.expirations.is_expired(key, clock.now) {
+            return Ok(None);
+        }
+        Ok(self.store.get(key)?)
+    }
+
+    fn write_locked(&self, log: &mut LogState, entry: LogEntry) -> Result<Option<Vec<u8>>, KvError> {
+        if let Some(wal) = log.wal.as_mut() {
+            wal.append(&entry)?;
+        }
+        let old = self.apply(&entry)?;
+        log.offset += 1;
+        log.backlog.push_back(entry.clone());
+        while log.backlog.len() > log.backlog_size {
+            log.backlog.pop_front();
+        }
+        // 没有follower的时候会发送失败, 直接忽略
+        let _ = self.replication_tx.send(self.replication(log.offset, false, vec![entry]));
+        Ok(old)
+    }
+
+    // 把修改应用到存储和过期时间表
+    fn apply(&self, entry: &LogEntry) -> Result<Option<Vec<u8>>, KvError> {
+        let old = match &entry.op {
+            Some(log_entry::Op::Put(LogPut{key, value, expire_at})) => {
+                let old = self.store.set_expiring(key, value.clone(), *expire_at)?;
+                match &old {
+                    None => self.metrics.add_data(1, (key.len() + value.len()) as i64),
+                    Some(old) => self.metrics.add_data(0, value.len() as i64 - old.len() as i64),
+                }
+                match expire_at {
+                    0 => { self.expirations.clear(key); }
+                    deadline => self.expirations.set_at(key, *deadline),
+                }
+                old
+            }
+            Some(log_entry::Op::Delete(LogDelete{key})) => {
+                self.expirations.clear(key);
+                let old = self.store.del(key)?;
+                if let Some(old) = &old {
+                    self.metrics.add_data(-1, -((key.len() + old.len()) as i64));
+                }
+                old
+            }
+            Some(log_entry::Op::Expire(LogExpire{key, expire_at})) => {
+                self.store.set_deadline(key, *expire_at)?;
+                match expire_at {
+                    0 => { self.expirations.clear(key); }
+                    deadline => self.expirations.set_at(key, *deadline),
+                }
+                None
+            }
+            Some(log_entry::Op::Batch(LogBatch{entries})) => {
+                for entry in entries {
+                    self.apply(entry)?;
+                }
+                None
+            }
+            None => None,
+        };
+        Ok(old)
+    }
+
+    // 本地请求使用的时间, 集群模式下由leader定时写入日志来删除过期的key
+    fn clock(&self) -> Clock {
+        Clock { now: now_ms(), reap: self.cluster().is_none() }
+    }
+
+    /// 删除所有已经过期的key, 返回删除的数量, 集群模式下什么都不做
+    pub fn reap_expired(&self) -> Result<usize, KvError> {
+        self.reap(self.clock())
+    }
+
+    /// 集群模式下执行到leader写入的空记录时, 删除在记录的时间已经过期的key
+    pub(crate) fn reap_expired_at(&self, timestamp: u64) -> Result<usize, KvError> {
+        self.reap(Clock { now: timestamp, reap: true })
+    }
+
+    /// 是否有已经过期还没有删除的key
+    pub(crate) fn has_expired(&self, now: u64) -> bool {
+        self.expirations.has_expired(now)
+    }
+
+    fn reap(&self, clock: Clock) -> Result<usize, KvError> {
+        if !clock.reap {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for key in self.expirations.expired(clock.now) {
+            if self.expire_if_needed(&key, clock)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // 访问key之前先检查是否过期, 过期时返回true, clock.reap为true时同时删除
+    fn expire_if_needed(&self, key: &str, clock: Clock) -> Result<bool, KvError> {
+        if !self.expirations.is_expired(key, clock.now) {
+            return Ok(false);
+        }
+        if !clock.reap {
+            return Ok(true);
+        }
+        // 拿到锁之后再检查一次, 避免删掉刚刚重新写入的值
+        let mut log = self.lock_log();
+        if !self.expirations.remove_expired(key, clock.now) {
+            return Ok(false);
+        }
+        // follower上只在本地删除, leader过期删除的记录同步过来时是空操作
+        match self.is_follower() {
+            true => self.apply(&LogEntry::delete(key))?,
+            false => self.write_locked(&mut log, LogEntry::delete(key))?,
+        };
+        Ok(true)
+    }
+
+    // 事务先在持有锁时逐个执行, 修改只记录在view中, 全部成功之后作为一条记录写入
+    // 锁只保证事务之间和其它写操作之间的顺序, 不持有锁的读请求可能看到写入到一半的状态
+    fn txn(&self, ops: Vec<TxnOp>, clock: Clock) -> Result<Response, KvError> {
+        let mut log = match ops.iter().any(|op| op.is_write()) {
+            true => self.lock_writable()?,
+            false => self.lock_log(),
+        };
+        // 事务中修改过的key: key -> (值, 过期时间), None表示已经删除
+        let mut view: HashMap<String, Option<(Vec<u8>, u64)>> = HashMap::new();
+        let mut entries = Vec::new();
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let key = op.key().to_owned();
+            let current = match view.get(&key) {
+                Some(value) => value.clone(),
+                None => self.current_locked(&key, clock)?
+                    .map(|value| (value, self.expirations.deadline(&key).unwrap_or(0))),
+            };
+            let result = match op.op {
+                Some(txn_op::Op::Get(_)) => match current {
+                    None => Response::not_found(key),
+                    Some((value, _)) => Response::new(key, value),
+                },
+                Some(txn_op::Op::Check(TxnCheck{condition, ..})) => {
+                    let matched = match (condition, &current) {
+                        (Some(txn_check::Condition::Exists(exists)), current) => exists == current.is_some(),
+                        (Some(txn_check::Condition::Equals(expected)), Some((value, _))) => *value == expected,
+                        (Some(txn_check::Condition::Equals(_)), None) => false,
+                        (None, _) => return Err(KvError::InvalidCommand(format!("check of {} has no condition", key))),
+                    };
+                    if !matched {
+                        // 条件不满足, 已经执行的修改全部丢弃
+                        results.push(Response::conflict(key, current.map(|(value, _)| value).unwrap_or_default()));
+                        return Ok(Response::with_results(results));
+                    }
+                    Response::new(key, vec![])
+                }
+                Some(txn_op::Op::Put(ResponsePut{value, ttl, ..})) => {
+                    let deadline = expire_at(ttl, clock.now);
+                    entries.push(LogEntry::put(key.clone(), value.clone(), deadline));
+                    view.insert(key.clone(), Some((value.clone(), deadline)));
+                    Response::new(key, value)
+                }
+                Some(txn_op::Op::Delete(_)) => match current {
+                    None => Response::not_found(key),
+                    Some((value, _)) => {
+                        entries.push(LogEntry::delete(key.clone()));
+                        view.insert(key.clone(), None);
+                        Response::new(key, value)
+                    }
+                },
+                Some(txn_op::Op::Incr(RequestIncr{delta, ..})) => {
+                    let (current, deadline) = match current {
+                        None => (0, 0),
+                        Some((value, deadline)) => {
+                            let current = std::str::from_utf8(&value).ok()
+                                .and_then(|s| s.parse::<i64>().ok())
+                                .ok_or_else(|| KvError::InvalidCommand(format!("value of {} is not an integer", key)))?;
+                            (current, deadline)
+                        }
+                    };
+                    let number = current.checked_add(delta)
+                        .ok_or_else(|| KvError::InvalidCommand(format!("increment of {} would overflow", key)))?;
+                    entries.push(LogEntry::put(key.clone(), number.to_string(), deadline));
+                    view.insert(key.clone(), Some((number.to_string().into_bytes(), deadline)));
+                    Response::with_number(key, number)
+                }
+                None => return Err(KvError::InvalidCommand("empty transaction operation".into())),
+            };
+            results.push(result);
+        }
+        if !entries.is_empty() {
+            self.write_locked(&mut log, LogEntry::batch(entries))?;
+        }
+        Ok(Response::with_results(results))
+    }
+
+    // 根据请求的命令操作存储, 返回对应的响应
+    pub fn handle(&self, request: Request) -> Response {
+        self.handle_with(request, self.clock())
+    }
+
+    /// 集群模式下执行raft日志中的请求, 按leader写入记录时的时间计算过期, 每个节点的结果都一样
+    pub(crate) fn handle_at(&self, request: Request, timestamp: u64) -> Response {
+        self.handle_with(request, Clock { now: timestamp, reap: true })
+    }
+
+    fn handle_with(&self, request: Request, clock: Clock) -> Response {
+        match self.dispatch(request, clock) {
+            Ok(response) => response,
+            Err(e) => {
+                if e.code() >= 500 {
+                    error!("failed to handle request: {:?}", e);
+                }
+                e.into()
+            }
+        }
+    }
+
+    fn dispatch(&self, request: Request, clock: Clock) -> Result<Response, KvError> {
+        let store = &self.store;
+        let response = match request.command {
+            Some(Command::Get(RequestGet{key})) => {
+                match self.get_unexpired(&key, clock)? {
+                    None => Response::not_found(key),
+                    Some(v) => Response::new(key, v),
+                }
+            }
+            Some(Command::Put(ResponsePut{key, value, ttl})) => {
+                self.write(LogEntry::put(key.clone(), value.clone(), expire_at(ttl, clock.now)))?;
+                Response::new(key, value)
+            }
+            Some(Command::Cas(RequestCompareAndSwap{key, expected, value, ttl})) => {
+                let mut log = self.lock_writable()?;
+                match self.current_locked(&key, clock)? {
+                    None => Response::not_found(key),
+                    Some(current) if current != expected => Response::conflict(key, current),
+                    Some(_) => {
+                        self.write_locked(&mut log, LogEntry::put(key.clone(), value.clone(), expire_at(ttl, clock.now)))?;
+                        Response::new(key, value)
+                    }
+                }
+            }
+            Some(Command::PutIfAbsent(RequestPutIfAbsent{key, value, ttl})) => {
+                let mut log = self.lock_writable()?;
+                match self.current_locked(&key, clock)? {
+                    Some(current) => Response::conflict(key, current),
+                    None => {
+                        self.write_locked(&mut log, LogEntry::put(key.clone(), value.clone(), expire_at(ttl, clock.now)))?;
+                        Response::new(key, value)
+                    }
+                }
+            }
+            Some(Command::Incr(RequestIncr{key, delta})) => {
+                let mut log = self.lock_writable()?;
+                let (current, deadline) = match self.current_locked(&key, clock)? {
+                    None => (0, 0),
+                    Some(value) => {
+                        let current = std::str::from_utf8(&value).ok()
+                            .and_then(|s| s.parse::<i64>().ok())
+                            .ok_or_else(|| KvError::InvalidCommand(format!("value of {} is not an integer", key)))?;
+                        (current, self.expirations.deadline(&key).unwrap_or(0))
+                    }
+                };
+                let number = current.checked_add(delta)
+                    .ok_or_else(|| KvError::InvalidCommand(format!("increment of {} would overflow", key)))?;
+                self.write_locked(&mut log, LogEntry::put(key.clone(), number.to_string(), deadline))?;
+                Response::with_number(key, number)
+            }
+            Some(Command::Txn(RequestTxn{ops})) => self.txn(ops, clock)?,
+            Some(Command::Delete(RequestDelete{key})) => {
+                let expired = self.expire_if_needed(&key, clock)?;
+                match self.write(LogEntry::delete(key.clone()))? {
+                    Some(value) if !expired => Response::new(key, value),
+                    _ => Response::not_found(key),
+                }
+            }
+            Some(Command::Exists(RequestExists{key})) => {
+                match self.contains_unexpired(&key, clock)? {
+                    true => Response::new(key, vec![]),
+                    false => Response::not_found(key),
+                }
+            }
+            Some(Command::Keys(RequestKeys{prefix})) => {
+                self.reap(clock)?;
+                let mut pairs: Vec<Kvpair> = store.iter()?
+                    .filter(|pair| pair.key.starts_with(&prefix) && !self.expirations.is_expired(&pair.key, clock.now))
+                    .map(|pair| Kvpair::new(pair.key, vec![]))
+                    .collect();
+                pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                Response::with_pairs(pairs)
+            }
+            Some(Command::GetAll(RequestGetAll{keys})) => {
+                // 不存在的key不会出现在结果中
+                let mut pairs = Vec::with_capacity(keys.len());
+                for key in keys {
+                    if let Some(value) = self.get_unexpired(&key, clock)? {
+                        pairs.push(Kvpair::new(key, value));
+                    }
+                }
+                Response::with_pairs(pairs)
+            }
+            Some(Command::PutAll(RequestPutAll{pairs})) => {
+                // 作为一条记录写入日志, 重放时不会只恢复一部分
+                if !pairs.is_empty() {
+                    let entries = pairs.into_iter().map(|pair| LogEntry::put(pair.key, pair.value, 0)).collect();
+                    self.write(LogEntry::batch(entries))?;
+                }
+                Response::ok()
+            }
+            Some(Command::Expire(RequestExpire{key, ttl})) => {
+                match self.contains_unexpired(&key, clock)? {
+                    true => {
+                        self.write(LogEntry::expire(key.clone(), clock.now.saturating_add(ttl)))?;
+                        Response::with_ttl(key, ttl as i64)
+                    }
+                    false => Response::not_found(key),
+                }
+            }
+            Some(Command::Ttl(RequestTtl{key})) => {
+                match self.contains_unexpired(&key, clock)? {
+                    true => {
+                        let ttl = self.expirations.remaining(&key, clock.now).map(|t| t as i64).unwrap_or(-1);
+                        Response::with_ttl(key, ttl)
+                    }
+                    false => Response::not_found(key),
+                }
+            }
+            Some(Command::Persist(RequestPersist{key})) => {
+                match self.contains_unexpired(&key, clock)? {
+                    true => {
+                        self.write(LogEntry::expire(key.clone(), 0))?;
+                        Response::with_ttl(key, -1)
+                    }
+                    false => Response::not_found(key),
+                }
+            }
+            Some(Command::Publish(RequestPublish{topic, value})) => {
+                let count = self.broadcaster.publish(&topic, value);
+                debug!("publish to [{}] delivered to {} subscriptions", topic, count);
+                Response::ok()
+            }
+            // 订阅和复制需要绑定在连接上, 由连接处理
+            Some(Command::Subscribe(_)) | Some(Command::Unsubscribe(_)) | Some(Command::Replicate(_)) | Some(Command::Auth(_)) => {
+                return Err(KvError::InvalidCommand("command requires a connection".into()));
+            }
+            Some(Command::Raft(_)) | Some(Command::AddMember(_)) | Some(Command::RemoveMember(_)) => {
+                return Err(KvError::InvalidCommand("cluster mode is not enabled".into()));
+            }
+            None => return Err(KvError::InvalidCommand("empty request".into())),
+        };
+        Ok(response)
+    }
+
+    // 已经过期的key当作不存在
+    fn get_unexpired(&self, key: &str, clock: Clock) -> Result<Option<Vec<u8>>, KvError> {
+        match self.expire_if_needed(key, clock)? {
+            true => Ok(None),
+            false => Ok(self.store.get(key)?),
+        }
+    }
+
+    fn contains_unexpired(&self, key: &str, clock: Clock) -> Result<bool, KvError> {
+        match self.expire_if_needed(key, clock)? {
+            true => Ok(false),
+            false => Ok(self.store.contains(key)?),
+        }
+    }
+}
+
+/// 退出的信号, 见ServerState::shutdown
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// 等待退出, ServerState已经不存在时直接返回
+    pub async fn recv(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new(MemTable::new())
+    }
+}
+
+// 按条数和字节数把记录分成多条消息, 单条记录超过字节数时单独一条消息
+fn chunks(entries: impl Iterator<Item = LogEntry>) -> Vec<Vec<LogEntry>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = 0;
+    for entry in entries {
+        let len = entry.encoded_len();
+        if !chunk.is_empty() && (chunk.len() >= REPLICATION_CHUNK || size + len > REPLICATION_CHUNK_BYTES) {
+            chunks.push(std::mem::take(&mut chunk));
+            size = 0;
+        }
+        size += len;
+        chunk.push(entry);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+// 和redis一样, 没有带ttl的put会去掉之前的过期时间
+fn expire_at(ttl: u64, now: u64) -> u64 {
+    match ttl {
+        0 => 0,
+        ttl => now.saturating_add(ttl),
+    }
+}
+
+/// 一个连接对应一个Session, 订阅的消息通过tx推送给连接
+pub struct Session {
+    state: Arc<ServerState>,
+    tx: mpsc::Sender<Response>,
+    subscriptions: Vec<(String, u32)>,
+    identity: Option<Identity>,
+    // 认证之后的用户
+    user: Option<Arc<User>>,
+}
+
+impl Session {
+    pub fn new(state: Arc<ServerState>, tx: mpsc::Sender<Response>) -> Self {
+        Session {
+            state,
+            tx,
+            subscriptions: Vec::new(),
+            identity: None,
+            user: None,
+        }
+    }
+
+    /// 连接上已经确认的客户端身份, 比如TLS客户端证书中的CN, 匹配到用户时直接认证
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.user = match (self.state.acl(), &identity) {
+            (Some(acl), Some(identity)) => acl.identify(identity),
+            _ => None,
+        };
+        self.identity = identity;
+        self
+    }
+
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_deref()
+    }
+
+    /// 开启认证时检查当前用户的权限, 没有认证时只能执行Auth
+    pub fn authorize(&self, request: &Request) -> Result<(), KvError> {
+        if matches!(request.command, Some(Command::Auth(_))) {
+            return Ok(());
+        }
+        // 没有开启认证时raft消息和成员变更也只接受用节点密钥连接的, 否则任何客户端都可以修改term, 日志和成员
+        if self.state.acl().is_none() {
+            let cluster_command = matches!(request.command, Some(Command::Raft(_)) | Some(Command::AddMember(_)) | Some(Command::RemoveMember(_)));
+            return match self.state.cluster() {
+                Some(raft) if cluster_command && !raft.is_peer(self.identity.as_ref()) => {
+                    Err(KvError::Unauthenticated("cluster commands are only accepted from cluster peers".into()))
+                }
+                _ => Ok(()),
+            };
+        }
+        match &self.user {
+            Some(user) => user.check(request),
+            None => Err(KvError::Unauthenticated("authentication required".into())),
+        }
+    }
+
+    pub fn handle(&mut self, request: Request) -> Response {
+        match request.command {
+            Some(Command::Subscribe(RequestSubscribe{topic})) => {
+                let id = self.state.broadcaster.subscribe(&topic, self.tx.clone());
+                self.subscriptions.push((topic.clone(), id));
+                Response::subscribed(topic, id)
+            }
+            Some(Command::Unsubscribe(RequestUnsubscribe{topic, id})) => {
+                // 只能取消自己连接上的订阅
+                let index = self.subscriptions.iter().position(|(t, i)| t == &topic && *i == id);
+                match index {
+                    Some(index) => {
+                        self.subscriptions.swap_remove(index);
+                        self.state.broadcaster.unsubscribe(&topic, id);
+                        Response::subscribed(topic, id)
+                    }
+                    None => Response::not_found(topic),
+                }
+            }
+            // 认证失败时保留之前认证的用户
+            Some(Command::Auth(RequestAuth{username, password})) => {
+                let user = match self.state.acl() {
+                    Some(acl) => acl.authenticate(&username, &password),
+                    None => return KvError::InvalidCommand("authentication is not enabled".into()).into(),
+                };
+                match user {
+                    Some(user) => {
+                        self.user = Some(user);
+                        Response::ok()
+                    }
+                    None => KvError::Unauthenticated("invalid username or password".into()).into(),
+                }
+            }
+            _ => self.state.handle(request),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for (topic, id) in self.subscriptions.drain(..) {
+            self.state.broadcaster.unsubscribe(&topic, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delete_and_exists() {
+        let state = ServerState::default();
+        state.handle(Request::new_put("hello", b"world"));
+        assert_eq!(state.handle(Request::new_exists("hello")).code, 0);
+        let response = state.handle(Request::new_delete("hello"));
+        assert_eq!(response.value, b"world");
+        assert_eq!(state.handle(Request::new_exists("hello")).code, 404);
+        assert_eq!(state.handle(Request::new_delete("hello")).code, 404);
+    }
+
+    #[test]
+    fn test_data_metrics() {
+        let store = MemTable::new();
+        store.set("a", b"1".to_vec()).unwrap();
+        let state = ServerState::new(store);
+        assert_eq!((state.metrics().keys(), state.metrics().memory_bytes()), (1, 2));
+        state.handle(Request::new_put("hello", b"world"));
+        state.handle(Request::new_put("hello", b"w"));
+        assert_eq!((state.metrics().keys(), state.metrics().memory_bytes()), (2, 8));
+        state.handle(Request::new_delete("a"));
+        assert_eq!((state.metrics().keys(), state.metrics().memory_bytes()), (1, 6));
+    }
+
+    #[test]
+    fn test_keys_and_get_all() {
+        let state = ServerState::default();
+        state.handle(Request::new_put_all(vec![
+            Kvpair::new("user:2", "b"),
+            Kvpair::new("user:1", "a"),
+            Kvpair::new("order:1", "c"),
+        ]));
+        assert_eq!(state.offset(), 1);
+        let keys: Vec<_> = state.handle(Request::new_keys("user:")).pairs
+            .into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+        let pairs = state.handle(Request::new_get_all(&["user:1", "missing", "order:1"])).pairs;
+        assert_eq!(pairs, vec![Kvpair::new("user:1", "a"), Kvpair::new("order:1", "c")]);
+    }
+
+    #[tokio::test]
+    async fn test_session_subscribe() {
+        let state = Arc::new(ServerState::default());
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut session = Session::new(state.clone(), tx);
+        let id = session.handle(Request::new_subscribe("news")).subscription_id;
+
+        state.handle(Request::new_publish("news", b"hello"));
+        let message = rx.recv().await.unwrap();
+        assert_eq!((message.subscription_id, message.value), (id, b"hello".to_vec()));
+
+        assert_eq!(session.handle(Request::new_unsubscribe("news", id + 1)).code, 404);
+        assert_eq!(session.handle(Request::new_unsubscribe("news", id)).code, 0);
+        assert_eq!(state.handle(Request::new_subscribe("news")).code, 400);
+        assert_eq!(state.handle(Request::default()).code, 400);
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let state = ServerState::default();
+        state.handle(Request::new_put_ex("token", b"abc", 60_000));
+        assert!(state.handle(Request::new_ttl("token")).ttl > 59_000);
+        assert_eq!(state.handle(Request::new_persist("token")).ttl, -1);
+        assert_eq!(state.handle(Request::new_ttl("token")).ttl, -1);
+        assert_eq!(state.handle(Request::new_ttl("missing")).code, 404);
+        assert_eq!(state.handle(Request::new_expire("missing", 10)).code, 404);
+    }
+
+    #[test]
+    fn test_expired_key_removed() {
+        let state = ServerState::default();
+        state.handle(Request::new_put_ex("a", b"1", 60_000));
+        state.handle(Request::new_put("b", b"2"));
+        state.handle(Request::new_expire("b", 0));
+        assert_eq!(state.handle(Request::new_get("b")).code, 404);
+        assert_eq!(state.handle(Request::new_exists("b")).code, 404);
+
+        state.handle(Request::new_put("c", b"3"));
+        state.handle(Request::new_expire("c", 0));
+        assert_eq!(state.reap_expired().unwrap(), 1);
+        assert!(!state.store.contains("c").unwrap());
+        assert_eq!(state.handle(Request::new_get("a")).value, b"1");
+    }
+
+    #[test]
+    fn test_handle_at_log_time() {
+        // 重放很久以前的日志, 结果只取决于记录中的时间
+        let replay = || {
+            let state = ServerState::default();
+            state.handle_at(Request::new_put_ex("a", b"1", 100), 1000);
+            state.handle_at(Request::new_incr("a", 1), 1050);
+            state.handle_at(Request::new_incr("a", 1), 1100);
+            state.handle_at(Request::new_put_ex("b", b"2", 100), 1100);
+            assert_eq!(state.reap_expired_at(1200).unwrap(), 1);
+            state
+        };
+        let state = replay();
+        assert_eq!(state.handle(Request::new_get("a")).value, b"1");
+        assert!(!state.store.contains("b").unwrap());
+        assert_eq!(state.expirations.deadline("a"), None);
+        assert_eq!(replay().handle(Request::new_get("a")).value, b"1");
+    }
+
+    #[test]
+    fn test_wal_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || ServerState::default().with_wal(Wal::open(dir.path(), false).unwrap()).unwrap();
+        let state = open();
+        state.handle(Request::new_put("a", b"1"));
+        state.handle(Request::new_put_ex("b", b"2", 60_000));
+        state.snapshot().unwrap();
+        state.handle(Request::new_put("c", b"3"));
+        state.handle(Request::new_delete("a"));
+        drop(state);
+
+        let state = open();
+        assert_eq!(state.handle(Request::new_get("a")).code, 404);
+        assert_eq!(state.handle(Request::new_get("c")).value, b"3");
+        assert!(state.handle(Request::new_ttl("b")).ttl > 0);
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let state = ServerState::default();
+        assert_eq!(state.handle(Request::new_cas("lock", b"a", b"b", 0)).code, 404);
+        assert_eq!(state.handle(Request::new_put_if_absent("lock", b"a", 0)).code, 0);
+        let response = state.handle(Request::new_put_if_absent("lock", b"b", 0));
+        assert_eq!((response.code, response.value.as_slice()), (409, &b"a"[..]));
+        assert_eq!(state.handle(Request::new_cas("lock", b"x", b"b", 0)).code, 409);
+        assert_eq!(state.handle(Request::new_cas("lock", b"a", b"b", 0)).code, 0);
+        assert_eq!(state.handle(Request::new_get("lock")).value, b"b");
+
+        state.handle(Request::new_put_ex("counter", b"10", 60_000));
+        assert_eq!(state.handle(Request::new_incr("counter", 5)).number, 15);
+        assert_eq!(state.handle(Request::new_incr("counter", -20)).value, b"-5");
+        assert!(state.handle(Request::new_ttl("counter")).ttl > 0);
+        assert_eq!(state.handle(Request::new_incr("new", 1)).number, 1);
+        assert_eq!(state.handle(Request::new_incr("lock", 1)).code, 400);
+        state.handle(Request::new_put("max", i64::MAX.to_string().as_bytes()));
+        assert_eq!(state.handle(Request::new_incr("max", 1)).code, 400);
+    }
+
+    #[test]
+    fn test_txn() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || ServerState::default().with_wal(Wal::open(dir.path(), false).unwrap()).unwrap();
+        let state = open();
+        state.handle(Request::new_put("balance:a", b"100"));
+        let transfer = |amount: i64| Request::new_txn(vec![
+            TxnOp::exists("balance:a", true),
+            TxnOp::incr("balance:a", -amount),
+            TxnOp::incr("balance:b", amount),
+            TxnOp::get("balance:a"),
+        ]);
+        let response = state.handle(transfer(30));
+        assert_eq!(response.code, 0);
+        let numbers: Vec<_> = response.results.iter().map(|r| r.number).collect();
+        assert_eq!(numbers, vec![0, 70, 30, 0]);
+        assert_eq!(response.results[3].value, b"70");
+
+        // 条件不满足时之前的修改也不生效
+        let response = state.handle(Request::new_txn(vec![
+            TxnOp::put("balance:a", b"0", 0),
+            TxnOp::equals("balance:b", b"31"),
+            TxnOp::delete("balance:b"),
+        ]));
+        assert_eq!((response.code, response.results.len()), (409, 2));
+        assert_eq!(response.results[1].value, b"30");
+        assert_eq!(state.handle(Request::new_get("balance:a")).value, b"70");
+
+        // 出错时整个事务失败
+        state.handle(Request::new_put("name", b"alice"));
+        let response = state.handle(Request::new_txn(vec![TxnOp::delete("balance:b"), TxnOp::incr("name", 1)]));
+        assert_eq!(response.code, 400);
+        assert_eq!(state.handle(Request::new_get("balance:b")).value, b"30");
+
+        // 一个事务是一条记录
+        let offset = state.offset();
+        state.handle(transfer(10));
+        assert_eq!(state.offset(), offset + 1);
+        drop(state);
+        let state = open();
+        assert_eq!(state.handle(Request::new_get("balance:a")).value, b"60");
+        assert_eq!(state.handle(Request::new_get("balance:b")).value, b"40");
+    }
+
+    #[test]
+    fn test_concurrent_incr() {
+        let state = Arc::new(ServerState::default());
+        let threads: Vec<_> = (0..8).map(|_| {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    state.handle(Request::new_incr("counter", 1));
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(state.handle(Request::new_get("counter")).value, b"800");
+    }
+
+    #[test]
+    fn test_replicate_from() {
+        let state = ServerState::default().with_backlog(2);
+        state.handle(Request::new_put("a", b"1"));
+        state.handle(Request::new_put("b", b"2"));
+        state.handle(Request::new_put("c", b"3"));
+        let id = state.replication_id().to_owned();
+        // offset还在backlog中时只发送之后的修改
+        let (messages, _) = state.replicate_from(&id, 2).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].offset, messages[0].reset), (3, false));
+        assert_eq!(messages[0].entries, vec![LogEntry::put("c", "3", 0)]);
+        // 超出backlog或者replication_id不一致时全量同步
+        let (messages, _) = state.replicate_from(&id, 0).unwrap();
+        assert!(messages[0].reset);
+        assert_eq!(messages[0].entries.len(), 3);
+
+        let follower = ServerState::default();
+        follower.set_follower(true);
+        assert_eq!(follower.handle(Request::new_put("a", b"1")).code, 403);
+        let (messages, _) = state.replicate_from("other", 3).unwrap();
+        for message in messages {
+            follower.apply_replication(message).unwrap();
+        }
+        assert_eq!(follower.offset(), 3);
+        assert_eq!(follower.handle(Request::new_get("c")).value, b"3");
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use tracing::info;
+use crate::compression::{compress_if_smaller, decompress};
+use crate::protobuf::Kvpair;
+use super::Storage;
+
+// 存在这个key时, 存储中所有的value都以一个字节的格式标记开头
+const FORMAT_KEY: &str = "\0kv/storage-format";
+const FORMAT_VERSION: &[u8] = b"1";
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+/// 包在其它存储外面, 写入时压缩比较大的value, 读出时解压
+/// 每个value前面有一个字节标记是否压缩过, 存储中记录了这个格式,
+/// 第一次打开没有压缩过的存储时, 已有的value会一次性加上标记
+#[derive(Debug)]
+pub struct Compressed<S>(S);
+
+impl<S: Storage> Compressed<S> {
+    pub fn new(inner: S) -> Result<Self> {
+        if !Self::is_compressed(&inner)? {
+            let mut pairs: Vec<Kvpair> = inner.iter()?
+                .map(|pair| Kvpair::new(pair.key, [&[RAW], &pair.value[..]].concat()))
+                .collect();
+            if !pairs.is_empty() {
+                info!("add format flag to {} existing values", pairs.len());
+            }
+            // 和格式标记在同一个批次中写入, 磁盘存储不会只转换了一部分
+            pairs.push(Kvpair::new(FORMAT_KEY, FORMAT_VERSION.to_vec()));
+            inner.set_batch(pairs)?;
+        }
+        Ok(Self(inner))
+    }
+
+    /// 存储是否已经使用压缩的格式, 这样的存储不能再去掉压缩直接使用
+    pub fn is_compressed(inner: &S) -> Result<bool> {
+        match inner.get(FORMAT_KEY)? {
+            None => Ok(false),
+            Some(version) if version == FORMAT_VERSION => Ok(true),
+            Some(version) => Err(anyhow!("unknown storage format: {:?}", String::from_utf8_lossy(&version))),
+        }
+    }
+}
+
+fn encode(value: Vec<u8>) -> Vec<u8> {
+    match compress_if_smaller(&value) {
+        Some(compressed) => [&[LZ4], &compressed[..]].concat(),
+        None => [&[RAW], &value[..]].concat(),
+    }
+}
+
+fn decode(value: Vec<u8>) -> Result<Vec<u8>> {
+    match value.split_first() {
+        Some((&RAW, value)) => Ok(value.to_vec()),
+        Some((&LZ4, compressed)) => decompress(compressed, u32::MAX as usize),
+        Some((flag, _)) => Err(anyhow!("invalid value format: {}", flag)),
+        None => Err(anyhow!("value without format flag")),
+    }
+}
+
+// 格式标记对外不可见, 也不能被修改
+fn check_key(key: &str) -> Result<()> {
+    match key == FORMAT_KEY {
+        true => Err(anyhow!("key {:?} is reserved", key)),
+        false => Ok(()),
+    }
+}
+
+impl<S: Storage> Storage for Compressed<S> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        self.0.get(key)?.map(decode).transpose()
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        self.0.set(key, encode(value))?.map(decode).transpose()
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        check_key(key)?;
+        self.0.contains(key)
+    }
+
+    fn del(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        self.0.del(key)?.map(decode).transpose()
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Kvpair> + '_>> {
+        // 先全部解压, 有一个失败时返回错误
+        let pairs = self.0.iter()?
+            .filter(|pair| pair.key != FORMAT_KEY)
+            .map(|pair| Ok(Kvpair::new(pair.key, decode(pair.value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn set_batch(&self, pairs: Vec<Kvpair>) -> Result<()> {
+        let pairs = pairs.into_iter()
+            .map(|pair| {
+                check_key(&pair.key)?;
+                Ok(Kvpair::new(pair.key, encode(pair.value)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.0.set_batch(pairs)
+    }
+
+    fn set_expiring(&self, key: &str, value: Vec<u8>, deadline: u64) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        self.0.set_expiring(key, encode(value), deadline)?.map(decode).transpose()
+    }
+
+    fn set_deadline(&self, key: &str, deadline: u64) -> Result<()> {
+        check_key(key)?;
+        self.0.set_deadline(key, deadline)
+    }
+
+    fn deadlines(&self) -> Result<Vec<(String, u64)>> {
+        self.0.deadlines()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use crate::protobuf::Kvpair;
+use super::Storage;
+
+/// 基于DashMap的内存存储, 重启后数据丢失
+#[derive(Debug, Default)]
+pub struct MemTable {
+    table: DashMap<String, Vec<u8>>,
+}
+
+impl MemTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemTable {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.table.get(key).map(|v| v.value().clone()))
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.table.insert(key.to_owned(), value))
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.table.contains_key(key))
+    }
+
+    fn del(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.table.remove(key).map(|(_, v)| v))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Kvpair> + '_>> {
+        let iter = self.table.iter()
+            .map(|entry| Kvpair::new(entry.key().clone(), entry.value().clone()));
+        Ok(Box::new(iter))
+    }
+}
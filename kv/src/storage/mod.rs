@@ -0,0 +1,151 @@
+mod compressed;
+mod memory;
+mod sleddb;
+
+use anyhow::Result;
+use crate::protobuf::Kvpair;
+
+pub use compressed::Compressed;
+pub use memory::MemTable;
+pub use sleddb::SledDb;
+
+/// 存储的抽象, 服务端只通过这个trait访问数据, 具体用内存还是磁盘在启动时决定
+pub trait Storage: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// 写入数据, 返回旧的值
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn contains(&self, key: &str) -> Result<bool>;
+    /// 删除数据, 返回被删除的值
+    fn del(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Kvpair> + '_>>;
+    /// 一次写入多个数据, 磁盘存储保证要么全部写入要么都没有写入
+    fn set_batch(&self, pairs: Vec<Kvpair>) -> Result<()> {
+        for pair in pairs {
+            self.set(&pair.key, pair.value)?;
+        }
+        Ok(())
+    }
+    /// 写入数据并保存过期的绝对时间(毫秒), 0表示不过期, 返回旧的值
+    /// 磁盘存储需要和数据一起原子地保存, 默认不保存, 内存存储重启之后数据也不存在了
+    fn set_expiring(&self, key: &str, value: Vec<u8>, deadline: u64) -> Result<Option<Vec<u8>>> {
+        let _ = deadline;
+        self.set(key, value)
+    }
+    /// 修改已经存在的key的过期时间, 0表示不过期
+    fn set_deadline(&self, _key: &str, _deadline: u64) -> Result<()> {
+        Ok(())
+    }
+    /// 保存的所有过期时间, 启动时用来恢复过期时间表, del时过期时间也一起删除
+    fn deadlines(&self) -> Result<Vec<(String, u64)>> {
+        Ok(Vec::new())
+    }
+    /// 把缓存的修改写到磁盘, 内存存储什么都不做
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_memtable_basic() {
+        test_basic(MemTable::new());
+    }
+
+    #[test]
+    fn test_sleddb_basic() {
+        let dir = tempfile::tempdir().unwrap();
+        test_basic(SledDb::new(dir.path()).unwrap());
+    }
+
+    // sled在后台线程退出之后才释放文件锁, 刚关闭时重新打开可能失败
+    fn reopen(path: &Path) -> SledDb {
+        for _ in 0..50 {
+            if let Ok(store) = SledDb::new(path) {
+                return store;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        SledDb::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_sleddb_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        store.set("hello", b"world".to_vec()).unwrap();
+        drop(store);
+        let store = reopen(dir.path());
+        assert_eq!(store.get("hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_sleddb_deadlines() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Compressed::new(SledDb::new(dir.path()).unwrap()).unwrap();
+        store.set_expiring("a", b"1".to_vec(), 100).unwrap();
+        store.set_expiring("b", b"2".to_vec(), 200).unwrap();
+        store.set_expiring("c", b"3".to_vec(), 300).unwrap();
+        store.set_expiring("b", b"2".to_vec(), 0).unwrap();
+        store.set_deadline("c", 400).unwrap();
+        store.del("a").unwrap();
+        drop(store);
+
+        let store = Compressed::new(reopen(dir.path())).unwrap();
+        assert_eq!(store.deadlines().unwrap(), vec![("c".to_owned(), 400)]);
+        assert_eq!(store.get("b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_compressed_basic() {
+        test_basic(Compressed::new(MemTable::new()).unwrap());
+    }
+
+    #[test]
+    fn test_compressed_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = br#"{"name":"kv","tags":["a","b"]}"#.repeat(100);
+        // 开启压缩之前写入的数据, 内容和压缩的格式标记一样也能原样读出
+        let raw = SledDb::new(dir.path()).unwrap();
+        raw.set("plain", b"hello".to_vec()).unwrap();
+        raw.set("flagged", b"\x01abc".to_vec()).unwrap();
+        assert!(!Compressed::is_compressed(&raw).unwrap());
+        let store = Compressed::new(raw).unwrap();
+        store.set("json", json.clone()).unwrap();
+        assert_eq!(store.iter().unwrap().count(), 3);
+        drop(store);
+
+        let raw = reopen(dir.path());
+        assert!(Compressed::is_compressed(&raw).unwrap());
+        assert!(raw.get("json").unwrap().unwrap().len() < json.len());
+        // 损坏的value读取时返回错误, 而不是被跳过
+        raw.set("broken", b"\x01xx".to_vec()).unwrap();
+        let store = Compressed::new(raw).unwrap();
+        assert_eq!(store.get("json").unwrap(), Some(json));
+        assert_eq!(store.get("plain").unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(store.get("flagged").unwrap(), Some(b"\x01abc".to_vec()));
+        assert!(store.iter().is_err());
+        assert!(store.set("\0kv/storage-format", vec![]).is_err());
+    }
+
+    fn test_basic(store: impl Storage) {
+        assert_eq!(store.set("k1", b"v1".to_vec()).unwrap(), None);
+        assert_eq!(store.set("k1", b"v2".to_vec()).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(store.get("k1").unwrap(), Some(b"v2".to_vec()));
+        assert!(store.contains("k1").unwrap());
+        store.set("k2", b"v3".to_vec()).unwrap();
+
+        let mut pairs: Vec<_> = store.iter().unwrap().collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, vec![Kvpair::new("k1", "v2"), Kvpair::new("k2", "v3")]);
+
+        assert_eq!(store.del("k1").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(store.del("k1").unwrap(), None);
+        assert!(!store.contains("k1").unwrap());
+        assert_eq!(store.get("k1").unwrap(), None);
+    }
+}
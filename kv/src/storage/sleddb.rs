@@ -0,0 +1,105 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use crate::protobuf::Kvpair;
+use super::Storage;
+
+// 过期时间保存在单独的tree中, 和数据在同一个事务中修改
+const DEADLINES_TREE: &str = "deadlines";
+
+/// 基于sled的磁盘存储, 重启后数据和过期时间仍然存在
+#[derive(Debug)]
+pub struct SledDb {
+    db: sled::Db,
+    // key -> 过期的绝对时间(毫秒), 大端的u64
+    deadlines: sled::Tree,
+}
+
+impl SledDb {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::open(path)?;
+        let deadlines = db.open_tree(DEADLINES_TREE)?;
+        Ok(Self { db, deadlines })
+    }
+}
+
+fn transaction_error(e: TransactionError) -> anyhow::Error {
+    match e {
+        TransactionError::Abort(e) | TransactionError::Storage(e) => e.into(),
+    }
+}
+
+impl Storage for SledDb {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.insert(key, value)?.map(|v| v.to_vec()))
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn del(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let old = (&*self.db, &self.deadlines).transaction(|(db, deadlines)| {
+            deadlines.remove(key)?;
+            Ok::<_, ConflictableTransactionError>(db.remove(key)?)
+        }).map_err(transaction_error)?;
+        Ok(old.map(|v| v.to_vec()))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Kvpair> + '_>> {
+        // 快照和日志压缩会用迭代的结果替换掉原来的数据, 读失败时返回错误, 不能跳过
+        let pairs = self.db.iter()
+            .map(|item| item.map(|(k, v)| Kvpair::new(String::from_utf8_lossy(&k), v.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn set_batch(&self, pairs: Vec<Kvpair>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for pair in pairs {
+            batch.insert(pair.key.as_str(), pair.value);
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn set_expiring(&self, key: &str, value: Vec<u8>, deadline: u64) -> Result<Option<Vec<u8>>> {
+        let old = (&*self.db, &self.deadlines).transaction(|(db, deadlines)| {
+            match deadline {
+                0 => deadlines.remove(key)?,
+                deadline => deadlines.insert(key, &deadline.to_be_bytes())?,
+            };
+            Ok::<_, ConflictableTransactionError>(db.insert(key, value.as_slice())?)
+        }).map_err(transaction_error)?;
+        Ok(old.map(|v| v.to_vec()))
+    }
+
+    fn set_deadline(&self, key: &str, deadline: u64) -> Result<()> {
+        match deadline {
+            0 => self.deadlines.remove(key)?,
+            deadline => self.deadlines.insert(key, &deadline.to_be_bytes())?,
+        };
+        Ok(())
+    }
+
+    fn deadlines(&self) -> Result<Vec<(String, u64)>> {
+        self.deadlines.iter()
+            .map(|item| {
+                let (key, deadline) = item?;
+                let deadline = deadline.as_ref().try_into()
+                    .map_err(|_| anyhow!("invalid deadline of {:?}", String::from_utf8_lossy(&key)))?;
+                Ok((String::from_utf8_lossy(&key).into_owned(), u64::from_be_bytes(deadline)))
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
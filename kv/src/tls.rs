@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{SinkExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::codec::LengthDelimitedCodec;
+use x509_parser::extensions::GeneralName;
+use crate::noise_codec::MAX_MESSAGE_LEN;
+use crate::server::Transport;
+
+// TLS本身会分片, 每个消息只需要加上4字节的长度
+const HEADER_LEN: usize = 4;
+
+/// TLS的服务端, 配置了client_ca时要求客户端提供这个CA签发的证书(mTLS)
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+    max_message_len: usize,
+}
+
+impl TlsServer {
+    /// 从PEM文件加载服务端的证书链和私钥
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(TlsServer {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            max_message_len: MAX_MESSAGE_LEN,
+        })
+    }
+
+    /// 限制单个消息的大小, 超过时连接会断开
+    pub fn max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len.max(1);
+        self
+    }
+
+    /// 完成TLS握手, 客户端提供了证书时同时返回证书中的身份
+    pub async fn accept<T>(&self, stream: T) -> Result<(impl Transport, Option<String>)>
+        where T: AsyncRead + AsyncWrite + Unpin
+    {
+        let stream = self.acceptor.accept(stream).await?;
+        let identity = stream.get_ref().1.peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(identity);
+        Ok((framed(stream, self.max_message_len), identity))
+    }
+}
+
+/// TLS的客户端, 用ca校验服务端的证书, 指定了客户端证书时用于mTLS
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName,
+    max_message_len: usize,
+}
+
+impl TlsClient {
+    /// server_name需要和服务端证书中的域名或者ip一致
+    pub fn new(ca: &str, server_name: &str, identity: Option<(&str, &str)>) -> Result<Self> {
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(server_name).map_err(|_| anyhow!("invalid server name: {}", server_name))?,
+            max_message_len: MAX_MESSAGE_LEN,
+        })
+    }
+
+    /// 限制单个消息的大小, 超过时连接会断开
+    pub fn max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len.max(1);
+        self
+    }
+
+    pub async fn connect<T>(&self, stream: T) -> Result<impl Transport>
+        where T: AsyncRead + AsyncWrite + Unpin
+    {
+        let stream = self.connector.connect(self.server_name.clone(), stream).await?;
+        Ok(framed(stream, self.max_message_len))
+    }
+}
+
+// 按4字节长度分割消息, 错误统一转换成anyhow
+fn framed<T>(stream: T, max_message_len: usize) -> impl Transport
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let framed = LengthDelimitedCodec::builder()
+        .length_field_length(HEADER_LEN)
+        .max_frame_length(max_message_len)
+        .new_framed(stream)
+        .map_err(|e: std::io::Error| anyhow::Error::from(e));
+    SinkExt::<Bytes>::sink_map_err(framed, |e: std::io::Error| anyhow::Error::from(e))
+}
+
+/// 证书中的身份, 优先使用subject的CN, 没有时使用第一个DNS类型的SAN
+pub fn identity(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    if let Some(cn) = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+        return Some(cn.to_owned());
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {:?}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// 支持PKCS#8, PKCS#1(RSA)和SEC1(EC)格式的私钥, 使用文件中的第一个
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        if let rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) = item {
+            return Ok(PrivateKey(key));
+        }
+    }
+    Err(anyhow!("no private key found in {:?}", path))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use futures::StreamExt;
+    use rcgen::{BasicConstraints, Certificate as Cert, CertificateParams, DnType, IsCa};
+    use tokio::io::duplex;
+    use super::*;
+
+    // 生成CA, 服务端证书和CN为alice的客户端证书
+    fn generate(dir: &Path) -> impl Fn(&str) -> String + '_ {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Cert::from_params(params).unwrap();
+        let server = Cert::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let client = Cert::from_params(params).unwrap();
+        let files = [
+            ("ca.pem", ca.serialize_pem().unwrap()),
+            ("server.pem", server.serialize_pem_with_signer(&ca).unwrap()),
+            ("server.key", server.serialize_private_key_pem()),
+            ("client.pem", client.serialize_pem_with_signer(&ca).unwrap()),
+            ("client.key", client.serialize_private_key_pem()),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        move |name| dir.join(name).to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = generate(dir.path());
+        let server = TlsServer::new(&path("server.pem"), &path("server.key"), Some(&path("ca.pem"))).unwrap();
+
+        let client = TlsClient::new(&path("ca.pem"), "localhost", Some((&path("client.pem"), &path("client.key")))).unwrap();
+        let (a, b) = duplex(4096);
+        let (client, accepted) = tokio::join!(client.connect(a), server.accept(b));
+        let (mut client, (mut server_stream, identity)) = (client.unwrap(), accepted.unwrap());
+        assert_eq!(identity.as_deref(), Some("alice"));
+        client.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(&server_stream.next().await.unwrap().unwrap()[..], b"hello");
+
+        // 没有客户端证书时服务端拒绝握手
+        let client = TlsClient::new(&path("ca.pem"), "localhost", None).unwrap();
+        let (a, b) = duplex(4096);
+        let (_, accepted) = tokio::join!(client.connect(a), server.accept(b));
+        assert!(accepted.is_err());
+    }
+}
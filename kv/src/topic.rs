@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use dashmap::{DashMap, DashSet};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use crate::protobuf::Response;
+
+/// topic的发布订阅, 每个订阅对应一个连接上的发送通道
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    // topic -> 订阅id
+    topics: DashMap<String, DashSet<u32>>,
+    // 订阅id -> 连接的发送通道
+    subscriptions: DashMap<u32, mpsc::Sender<Response>>,
+    next_id: AtomicU32,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 订阅topic, 返回订阅id, 之后发布的消息会发送到tx
+    pub fn subscribe(&self, topic: &str, tx: mpsc::Sender<Response>) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.subscriptions.insert(id, tx);
+        self.topics.entry(topic.to_owned()).or_default().insert(id);
+        debug!("subscription {} added to topic [{}]", id, topic);
+        id
+    }
+
+    /// 取消订阅, 订阅不存在时返回false
+    pub fn unsubscribe(&self, topic: &str, id: u32) -> bool {
+        let removed = match self.topics.get(topic) {
+            Some(ids) => ids.remove(&id).is_some(),
+            None => false,
+        };
+        if removed {
+            self.subscriptions.remove(&id);
+            self.topics.remove_if(topic, |_, ids| ids.is_empty());
+        }
+        removed
+    }
+
+    /// 发布消息, 返回收到消息的订阅数量
+    pub fn publish(&self, topic: &str, value: Vec<u8>) -> usize {
+        let ids: Vec<u32> = match self.topics.get(topic) {
+            Some(ids) => ids.iter().map(|id| *id).collect(),
+            None => return 0,
+        };
+        let mut count = 0;
+        for id in ids {
+            let tx = match self.subscriptions.get(&id) {
+                Some(tx) => tx.clone(),
+                None => continue,
+            };
+            let message = Response {
+                key: topic.to_owned(),
+                value: value.clone(),
+                subscription_id: id,
+                ..Default::default()
+            };
+            match tx.try_send(message) {
+                Ok(_) => count += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // 订阅方消费太慢时丢弃消息, 不能阻塞发布方
+                    warn!("subscription {} is full, drop message on topic [{}]", id, topic);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    self.unsubscribe(topic, id);
+                }
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_and_unsubscribe() {
+        let broadcaster = Broadcaster::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        let id = broadcaster.subscribe("news", tx.clone());
+        broadcaster.subscribe("other", tx);
+
+        assert_eq!(broadcaster.publish("news", b"hello".to_vec()), 1);
+        let message = rx.recv().await.unwrap();
+        assert_eq!((message.key.as_str(), message.value.as_slice(), message.subscription_id), ("news", &b"hello"[..], id));
+
+        assert!(broadcaster.unsubscribe("news", id));
+        assert!(!broadcaster.unsubscribe("news", id));
+        assert_eq!(broadcaster.publish("news", b"hello".to_vec()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_subscription_removed() {
+        let broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel(8);
+        broadcaster.subscribe("news", tx);
+        drop(rx);
+        assert_eq!(broadcaster.publish("news", b"hello".to_vec()), 0);
+        assert!(broadcaster.topics.is_empty());
+    }
+}
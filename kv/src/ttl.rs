@@ -0,0 +1,85 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+
+/// 当前的unix时间, 单位毫秒
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// key的过期时间表, 记录的是过期的绝对时间(毫秒)
+/// 磁盘存储同时保存过期时间, 启动时从存储恢复这个表
+/// 判断是否过期时由调用方传入当前时间, 集群模式下执行日志时用的是leader写入记录的时间
+#[derive(Debug, Default)]
+pub struct Expirations {
+    deadlines: DashMap<String, u64>,
+}
+
+impl Expirations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置过期的绝对时间
+    pub fn set_at(&self, key: &str, deadline: u64) {
+        self.deadlines.insert(key.to_owned(), deadline);
+    }
+
+    /// 过期的绝对时间
+    pub fn deadline(&self, key: &str) -> Option<u64> {
+        self.deadlines.get(key).map(|d| *d)
+    }
+
+    pub fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.deadlines.get(key).map(|d| *d <= now).unwrap_or(false)
+    }
+
+    /// 去掉过期时间, 之前有过期时间时返回true
+    pub fn clear(&self, key: &str) -> bool {
+        self.deadlines.remove(key).is_some()
+    }
+
+    /// 剩余的毫秒数, 没有过期时间时返回None
+    pub fn remaining(&self, key: &str, now: u64) -> Option<u64> {
+        self.deadlines.get(key).map(|deadline| deadline.saturating_sub(now))
+    }
+
+    /// 如果key已经过期就把它从表中移除并返回true, 调用方负责从存储中删除
+    pub fn remove_expired(&self, key: &str, now: u64) -> bool {
+        self.deadlines.remove_if(key, |_, deadline| *deadline <= now).is_some()
+    }
+
+    /// 所有已经过期的key
+    pub fn expired(&self, now: u64) -> Vec<String> {
+        self.deadlines.iter()
+            .filter(|entry| *entry.value() <= now)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// 是否有已经过期的key
+    pub fn has_expired(&self, now: u64) -> bool {
+        self.deadlines.iter().any(|entry| *entry.value() <= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expirations() {
+        let expirations = Expirations::new();
+        expirations.set_at("a", 1000);
+        expirations.set_at("b", 61_000);
+        assert!(!expirations.has_expired(999));
+        assert_eq!(expirations.expired(1000), vec!["a".to_owned()]);
+        assert_eq!(expirations.remaining("b", 1000), Some(60_000));
+        assert!(!expirations.remove_expired("b", 1000));
+        assert!(expirations.remove_expired("a", 1000));
+        assert!(expirations.remaining("a", 1000).is_none());
+        assert!(expirations.clear("b"));
+        assert!(!expirations.clear("b"));
+    }
+}
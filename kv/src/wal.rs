@@ -0,0 +1,187 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use prost::encoding::decode_varint;
+use prost::Message;
+use tracing::{info, warn};
+use crate::protobuf::LogEntry;
+
+const LOG_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+// varint最多占10个字节
+const MAX_VARINT_LEN: usize = 10;
+
+/// 预写日志, 每个修改在生效之前先追加到wal.log,
+/// 快照把整个数据集写到snapshot之后清空wal.log, 启动时先加载快照再重放日志
+/// 日志和快照都是length delimited的LogEntry
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    log: File,
+    // 每次追加之后是否fsync
+    sync: bool,
+}
+
+impl Wal {
+    pub fn open(dir: impl AsRef<Path>, sync: bool) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let log = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        Ok(Wal { dir, log, sync })
+    }
+
+    pub fn append(&mut self, entry: &LogEntry) -> Result<()> {
+        self.log.write_all(&entry.encode_length_delimited_to_vec())?;
+        if self.sync {
+            self.log.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// 把已经追加的记录fsync到磁盘
+    pub fn sync(&mut self) -> Result<()> {
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    /// 读取快照和日志中的所有记录, 按顺序重放就能恢复数据
+    /// 日志末尾不完整的记录会被截掉, 保证之后追加的记录可以被读到, 其它损坏返回错误
+    pub fn replay(&mut self) -> Result<Vec<LogEntry>> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let (mut entries, valid) = read_entries(&path)?;
+        // 快照是写完之后rename的, 不会只写了一半
+        if fs::metadata(&path).map(|m| m.len() > valid).unwrap_or(false) {
+            return Err(anyhow!("snapshot {:?} is incomplete", path));
+        }
+        let snapshot = entries.len();
+        let (log, valid) = read_entries(&self.dir.join(LOG_FILE))?;
+        if valid < self.log.metadata()?.len() {
+            self.log.set_len(valid)?;
+        }
+        entries.extend(log);
+        info!("replay {} entries from snapshot and {} from wal", snapshot, entries.len() - snapshot);
+        Ok(entries)
+    }
+
+    /// 写入新的快照, 成功之后清空日志
+    /// 调用方需要保证写快照的时候没有新的修改
+    pub fn snapshot(&mut self, entries: impl Iterator<Item = LogEntry>) -> Result<usize> {
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut count = 0;
+        for entry in entries {
+            writer.write_all(&entry.encode_length_delimited_to_vec())?;
+            count += 1;
+        }
+        writer.into_inner()?.sync_all()?;
+        // rename是原子的, 中途崩溃时旧的快照和日志仍然完整
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        // rename要在目录fsync之后才持久化, 否则清空日志之后崩溃可能还是旧的快照
+        sync_dir(&self.dir)?;
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        Ok(count)
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+// 返回所有完整的记录和它们占用的字节数, 只有文件末尾不完整的记录(写到一半时崩溃)会被忽略,
+// 中间的记录损坏时返回错误, 截掉的话会丢掉后面完整的记录
+fn read_entries(path: &Path) -> Result<(Vec<LogEntry>, u64)> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(file) => BufReader::new(file).read_to_end(&mut buf)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    let mut remaining = &buf[..];
+    while !remaining.is_empty() {
+        let valid = buf.len() - remaining.len();
+        let mut body = remaining;
+        let len = match decode_varint(&mut body) {
+            Ok(len) => len as usize,
+            // 长度前缀只写了一部分
+            Err(_) if remaining.len() < MAX_VARINT_LEN && remaining.iter().all(|b| b & 0x80 != 0) => {
+                warn!("ignore broken tail of {:?} at offset {}", path, valid);
+                break;
+            }
+            Err(e) => return Err(anyhow!("corrupted record in {:?} at offset {}: {}", path, valid, e)),
+        };
+        if len > body.len() {
+            warn!("ignore broken tail of {:?} at offset {}", path, valid);
+            break;
+        }
+        let entry = LogEntry::decode(&body[..len])
+            .map_err(|e| anyhow!("corrupted record in {:?} at offset {}: {}", path, valid, e))?;
+        entries.push(entry);
+        remaining = &body[len..];
+    }
+    Ok((entries, (buf.len() - remaining.len()) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path(), false).unwrap();
+        wal.append(&LogEntry::put("a", "1", 0)).unwrap();
+        wal.append(&LogEntry::delete("a")).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(wal.replay().unwrap(), vec![LogEntry::put("a", "1", 0), LogEntry::delete("a")]);
+    }
+
+    #[test]
+    fn test_snapshot_truncates_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path(), true).unwrap();
+        wal.append(&LogEntry::put("a", "1", 0)).unwrap();
+        wal.snapshot(vec![LogEntry::put("a", "1", 0)].into_iter()).unwrap();
+        wal.append(&LogEntry::put("b", "2", 0)).unwrap();
+        assert_eq!(wal.replay().unwrap(), vec![LogEntry::put("a", "1", 0), LogEntry::put("b", "2", 0)]);
+    }
+
+    #[test]
+    fn test_broken_tail_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path(), false).unwrap();
+        wal.append(&LogEntry::put("a", "1", 0)).unwrap();
+        // 模拟写到一半崩溃
+        let half = LogEntry::put("b", "2", 0).encode_length_delimited_to_vec();
+        wal.log.write_all(&half[..half.len() / 2]).unwrap();
+        assert_eq!(wal.replay().unwrap(), vec![LogEntry::put("a", "1", 0)]);
+        // 截掉之后新追加的记录可以正常读到
+        wal.append(&LogEntry::put("c", "3", 0)).unwrap();
+        assert_eq!(wal.replay().unwrap(), vec![LogEntry::put("a", "1", 0), LogEntry::put("c", "3", 0)]);
+    }
+
+    #[test]
+    fn test_corrupted_record_not_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path(), false).unwrap();
+        wal.append(&LogEntry::put("a", "1", 0)).unwrap();
+        // 长度完整但是内容无法解码的记录, 后面还有完整的记录
+        wal.log.write_all(&[3, 0xff, 0xff, 0xff]).unwrap();
+        wal.append(&LogEntry::put("c", "3", 0)).unwrap();
+        let len = wal.log.metadata().unwrap().len();
+        assert!(wal.replay().is_err());
+        assert_eq!(wal.log.metadata().unwrap().len(), len);
+    }
+}